@@ -1,27 +1,288 @@
 // main.rs
-use chrono::{DateTime, Utc};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc, Weekday};
 use macroquad::prelude::*;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+use tungstenite::stream::MaybeTlsStream;
 
 #[derive(Debug, Clone)]
 struct StockData {
     symbol: String,
-    prices: Vec<f32>,
-    timestamps: Vec<DateTime<Utc>>,
-    prices_24h_ago: Vec<f32>,
-    timestamps_24h_ago: Vec<DateTime<Utc>>,
+    // Serie live (quote e tick), con min/max mantenuti a ogni punto
+    live: PriceRing,
+    // Candele OHLCV dello storico, per l'intervallo scelto in `range`
+    history: Vec<Candle>,
+    range: ChartRange,
     current_price: f32,
     change_percent: f32,
-    change_24h_percent: f32,
+    change_range_percent: f32,
 
     // Precalcolati per evitare fold ogni frame
-    current_min: f32,
-    current_max: f32,
-    h24_min: f32,
-    h24_max: f32,
+    hist_min: f32,
+    hist_max: f32,
+
+    // Ultimo errore di fetch (None dopo un aggiornamento riuscito)
+    last_error: Option<FetchError>,
+    // Valori ripresi dallo snapshot della sessione precedente, non ancora aggiornati
+    stale: bool,
+    // Orari della borsa (None per crypto e provider senza meta)
+    session: Option<TradingSession>,
+    // Prezzo pre/post-market (None durante la seduta regolare)
+    extended: Option<ExtendedQuote>,
+    // Valuta di quotazione dal provider (None = non riportata)
+    currency: Option<String>,
+    // Decimali significativi secondo il provider (priceHint di Yahoo)
+    price_hint: Option<u32>,
+}
+
+// Un giorno di quote al minuto
+const DEFAULT_LIVE_CAPACITY: usize = 24 * 60;
+// Dallo stream si aggiunge un punto alla serie al massimo ogni N secondi
+const STREAM_SAMPLE_SECS: i64 = 5;
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl StockData {
+    fn new(symbol: &str, live_capacity: usize) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            live: PriceRing::new(live_capacity),
+            history: vec![],
+            range: ChartRange::default(),
+            current_price: 0.0,
+            change_percent: 0.0,
+            change_range_percent: 0.0,
+            hist_min: f32::INFINITY,
+            hist_max: f32::NEG_INFINITY,
+            last_error: None,
+            stale: false,
+            session: None,
+            extended: None,
+            currency: None,
+            price_hint: None,
+        }
+    }
+
+    fn push_price(&mut self, price: f32, change: f32, time: DateTime<Utc>) {
+        self.live.push(time, price);
+        self.current_price = price;
+        self.change_percent = change;
+        self.last_error = None;
+        self.stale = false;
+    }
+
+    // I tick arrivano anche più volte al secondo: entro STREAM_SAMPLE_SECS
+    // dall'ultimo punto si aggiorna quello invece di aggiungerne uno nuovo
+    fn apply_tick(&mut self, tick: &StreamTick) {
+        let change = tick.change_percent.unwrap_or(self.change_percent);
+
+        match self.live.last_time() {
+            Some(last_time) if (tick.time - last_time).num_seconds() < STREAM_SAMPLE_SECS => {
+                self.live.set_last(tick.price);
+                self.current_price = tick.price;
+                self.change_percent = change;
+                self.last_error = None;
+                self.stale = false;
+            }
+            _ => self.push_price(tick.price, change, tick.time),
+        }
+    }
+
+    // Ripristina la serie live dall'archivio su disco (prima di ogni fetch).
+    // L'ultimo tick può essere di giorni fa: resta marcato come non aggiornato.
+    fn load_ticks(&mut self, ticks: &[(DateTime<Utc>, f32)]) {
+        let start = ticks.len().saturating_sub(self.live.capacity);
+        for &(time, price) in &ticks[start..] {
+            self.live.push(time, price);
+        }
+        if let Some(&(_, price)) = ticks.last() {
+            self.current_price = price;
+            self.stale = true;
+        }
+    }
+
+    // Stato salvato all'ultima uscita: mostrato subito, marcato come non aggiornato.
+    // La serie live dell'archivio tick, se c'è, ha la precedenza su quella dello snapshot.
+    fn restore(&mut self, entry: SnapshotEntry) {
+        if self.live.is_empty() {
+            let points = entry.prices.len().min(entry.timestamps.len());
+            let start = points.saturating_sub(self.live.capacity);
+            for i in start..points {
+                self.live.push(entry.timestamps[i], entry.prices[i]);
+            }
+        }
+        self.current_price = entry.current_price;
+        self.change_percent = entry.change_percent;
+        self.range = entry.range;
+        self.set_history(entry.history);
+        self.change_range_percent = entry.change_range_percent;
+        self.session = entry.session;
+        self.currency = entry.currency;
+        self.price_hint = entry.price_hint;
+        self.stale = true;
+    }
+
+    fn set_history(&mut self, candles: Vec<Candle>) {
+        if let Some(first) = candles.first() {
+            self.hist_min = candles.iter().map(|c| c.low).fold(f32::INFINITY, f32::min);
+            self.hist_max = candles
+                .iter()
+                .map(|c| c.high)
+                .fold(f32::NEG_INFINITY, f32::max);
+
+            let p24 = first.open;
+            if self.current_price > 0.0 && p24 > 0.0 {
+                self.change_range_percent = ((self.current_price - p24) / p24) * 100.0;
+            }
+        }
+        self.history = candles;
+        self.last_error = None;
+    }
+
+    fn apply_quote(&mut self, result: FetchResult<Quote>, time: DateTime<Utc>) {
+        let quote = match result {
+            Ok(quote) => quote,
+            Err(err) => {
+                self.last_error = Some(err);
+                return;
+            }
+        };
+
+        if quote.session.is_some() {
+            self.session = quote.session;
+            self.extended = quote.extended;
+        }
+        if quote.currency.is_some() {
+            self.currency = quote.currency;
+        }
+        if quote.price_hint.is_some() {
+            self.price_hint = quote.price_hint;
+        }
+        // A mercato fermo l'orario dell'ultimo scambio non cambia: aggiungere
+        // lo stesso prezzo a ogni giro appiattirebbe il grafico
+        let time = quote.market_time.unwrap_or(time);
+        if self.live.last_time().is_some_and(|last| time <= last) {
+            self.current_price = quote.price;
+            self.change_percent = quote.change_percent;
+            self.last_error = None;
+            self.stale = false;
+        } else {
+            self.push_price(quote.price, quote.change_percent, time);
+        }
+    }
+
+    fn apply_history(&mut self, result: FetchResult<History>, range: ChartRange) {
+        // Risposta arrivata dopo un cambio di intervallo: scartala
+        if range != self.range {
+            return;
+        }
+        match result {
+            Ok(candles) => self.set_history(candles),
+            Err(err) => self.last_error = Some(err),
+        }
+    }
+
+    fn set_range(&mut self, range: ChartRange) {
+        self.range = range;
+        self.history.clear();
+        self.hist_min = f32::INFINITY;
+        self.hist_max = f32::NEG_INFINITY;
+        self.change_range_percent = 0.0;
+    }
+}
+
+// Buffer circolare a capacità fissa: aggiungere un punto e scartare il più
+// vecchio costa O(1). Min e max si aggiornano a ogni punto e si ricalcolano
+// sull'intera serie solo quando esce (o viene sovrascritto) proprio un estremo.
+#[derive(Debug, Clone)]
+struct PriceRing {
+    times: VecDeque<DateTime<Utc>>,
+    prices: VecDeque<f32>,
+    capacity: usize,
+    min: f32,
+    max: f32,
+}
+
+impl PriceRing {
+    fn new(capacity: usize) -> Self {
+        // Le deque crescono con i punti: `capacity` è solo il tetto, così
+        // migliaia di simboli con pochi dati non riservano giorni di memoria
+        let capacity = capacity.max(2);
+        Self {
+            times: VecDeque::new(),
+            prices: VecDeque::new(),
+            capacity,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.prices.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    fn last_time(&self) -> Option<DateTime<Utc>> {
+        self.times.back().copied()
+    }
+
+    fn push(&mut self, time: DateTime<Utc>, price: f32) {
+        let evicted = if self.prices.len() == self.capacity {
+            self.times.pop_front();
+            self.prices.pop_front()
+        } else {
+            None
+        };
+        self.times.push_back(time);
+        self.prices.push_back(price);
+
+        match evicted {
+            Some(old) if old <= self.min || old >= self.max => self.recompute(),
+            _ => self.include(price),
+        }
+    }
+
+    // Sostituisce il prezzo dell'ultimo punto (tick ravvicinati dello stream)
+    fn set_last(&mut self, price: f32) {
+        let Some(last) = self.prices.back_mut() else {
+            return;
+        };
+        let old = std::mem::replace(last, price);
+        if (old <= self.min && price > old) || (old >= self.max && price < old) {
+            self.recompute();
+        } else {
+            self.include(price);
+        }
+    }
+
+    fn include(&mut self, price: f32) {
+        self.min = self.min.min(price);
+        self.max = self.max.max(price);
+    }
+
+    fn recompute(&mut self) {
+        self.min = self.prices.iter().copied().fold(f32::INFINITY, f32::min);
+        self.max = self
+            .prices
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -41,21 +302,74 @@ impl ScrollbarState {
     }
 }
 
+// La mappa dei titoli appartiene al render loop: i worker la aggiornano
+// solo tramite i messaggi StockUpdate ricevuti su `updates`
 #[derive(Debug)]
 struct App {
-    stocks: Arc<Mutex<HashMap<String, StockData>>>,
-    last_update: Arc<Mutex<DateTime<Utc>>>,
+    stocks: HashMap<String, StockData>,
+    updates: Receiver<StockUpdate>,
+    last_update: DateTime<Utc>,
     selected_symbols: HashSet<String>,
     scrollbar_state: ScrollbarState,
+    // Valute di quotazione già viste e quelle comparse dall'ultimo frame
+    currencies: HashSet<String>,
+    new_currencies: Vec<String>,
+    search: SearchBox,
 }
 
 impl App {
-    fn new() -> Self {
+    fn new(updates: Receiver<StockUpdate>) -> Self {
         Self {
-            stocks: Arc::new(Mutex::new(HashMap::new())),
-            last_update: Arc::new(Mutex::new(Utc::now())),
+            stocks: HashMap::new(),
+            updates,
+            last_update: Utc::now(),
             selected_symbols: HashSet::new(),
             scrollbar_state: ScrollbarState::new(),
+            currencies: HashSet::new(),
+            new_currencies: Vec::new(),
+            search: SearchBox::default(),
+        }
+    }
+
+    fn note_currency(&mut self, currency: &str) {
+        if self.currencies.insert(currency.to_string()) {
+            self.new_currencies.push(currency.to_string());
+        }
+    }
+
+    // Applica tutti gli aggiornamenti arrivati dall'ultimo frame
+    fn apply_updates(&mut self) {
+        while let Ok(update) = self.updates.try_recv() {
+            match update {
+                StockUpdate::Quote {
+                    symbol,
+                    result,
+                    time,
+                } => {
+                    if let Some(s) = self.stocks.get_mut(&symbol) {
+                        s.apply_quote(result, time);
+                        if let Some(currency) = s.currency.clone() {
+                            self.note_currency(&currency);
+                        }
+                    }
+                }
+                StockUpdate::History {
+                    symbol,
+                    range,
+                    result,
+                } => {
+                    if let Some(s) = self.stocks.get_mut(&symbol) {
+                        s.apply_history(result, range);
+                    }
+                }
+                StockUpdate::Tick(tick) => {
+                    if let Some(s) = self.stocks.get_mut(&tick.symbol) {
+                        s.apply_tick(&tick);
+                    }
+                }
+                StockUpdate::Polled(time) => self.last_update = time,
+                StockUpdate::Search { query, result } => self.search.set_results(query, result),
+            }
         }
     }
 }
@@ -63,853 +377,5278 @@ impl App {
 const MAX_SELECTED: usize = 8;
 
 // ────────────────────────────────────────────────
-// Fetch functions (invariate)
+// Configurazione (flag CLI > variabile d'ambiente > default)
 // ────────────────────────────────────────────────
 
-fn fetch_stock_data(symbol: &str) -> Result<(f32, f32), Box<dyn std::error::Error>> {
-    let url = format!(
-        "https://query1.finance.yahoo.com/v8/finance/chart/{}?interval=1m&range=1d",
-        symbol
-    );
+const DEFAULT_PROVIDERS: &str = "yahoo,stooq";
+const DEFAULT_YAHOO_URL: &str = "https://query1.finance.yahoo.com";
+const DEFAULT_STOOQ_URL: &str = "https://stooq.com";
+const DEFAULT_DATA_DIR: &str = "data";
+const DEFAULT_TICK_STORE_DIR: &str = "ticks";
+const DEFAULT_SNAPSHOT_PATH: &str = "snapshot.json";
+const DEFAULT_WATCHLIST_PATH: &str = "watchlist.txt";
+const DEFAULT_STREAM_URL: &str = "wss://streamer.finance.yahoo.com/?version=2";
+const DEFAULT_FETCH_CONCURRENCY: usize = 6;
+const DEFAULT_RATE_LIMIT: f64 = 4.0;
+const DEFAULT_RATE_BURST: f64 = 8.0;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_CRYPTO_INTERVAL: f64 = 10.0;
+const DEFAULT_HISTORY_INTERVAL: f64 = 300.0;
+const DEFAULT_OFFSCREEN_FACTOR: f64 = 5.0;
+const DEFAULT_RETENTION_HOURS: f64 = 72.0;
+// Tetto per intervalli di polling e snapshot: un giorno
+const MAX_INTERVAL_SECS: f64 = 86_400.0;
+// Dieci anni: abbondanti per qualsiasi archivio, e il cutoff resta calcolabile
+const MAX_RETENTION_HOURS: f64 = 24.0 * 365.0 * 10.0;
 
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(&url)
-        .header("User-Agent", "Mozilla/5.0")
-        .timeout(Duration::from_secs(10))
-        .send()?;
+#[derive(Debug, Clone)]
+struct Config {
+    // Catena di provider in ordine di preferenza, es. ["yahoo", "stooq"]
+    providers: Vec<String>,
+    // Host dell'endpoint chart, es. http://127.0.0.1:8080 per un mock locale
+    yahoo_base_url: String,
+    stooq_base_url: String,
+    // Cartella del provider "file": <SIMBOLO>.json o <SIMBOLO>.csv
+    data_dir: String,
+    // Parametri del provider "sim" e simboli sintetici aggiunti alla lista
+    sim: SimParams,
+    sim_symbols: usize,
+    // File con i simboli della lista (None = lista predefinita, non salvata)
+    watchlist_path: Option<String>,
+    // Elenco locale per la ricerca (None = ricerca tramite il provider)
+    symbols_file: Option<String>,
+    // Punti della serie live tenuti in memoria per simbolo
+    live_capacity: usize,
+    // Cartella dell'archivio tick (None = disattivato) e retention
+    tick_store_dir: Option<String>,
+    tick_retention: chrono::Duration,
+    // File dello snapshot per l'avvio immediato (None = disattivato) e ogni quanto salvarlo
+    snapshot_path: Option<String>,
+    snapshot_interval: Duration,
+    // Frequenza di aggiornamento per classe di strumento e visibilità
+    intervals: PollIntervals,
+    // File di festività aggiuntive (una data AAAA-MM-GG per riga)
+    holidays_path: Option<String>,
+    // Valuta in cui mostrare tutti i prezzi (None = valuta di quotazione)
+    base_currency: Option<String>,
+    // Sessione su cui registrare le risposte dei provider, o da riprodurre
+    record_path: Option<String>,
+    replay_path: Option<String>,
+    // Velocità del replay: 1x, 10x, 100x, …
+    replay_speed: f64,
+    // WebSocket dei prezzi in tempo reale (None = solo polling)
+    stream_url: Option<String>,
+    // Richieste parallele massime quando il provider non ha un endpoint batch
+    fetch_concurrency: usize,
+    // Token bucket globale: richieste al secondo e raffica massima
+    rate_limit: f64,
+    rate_burst: f64,
+    max_retries: u32,
+}
 
-    let json: serde_json::Value = response.json()?;
+impl Config {
+    fn load() -> Self {
+        let args: Vec<String> = std::env::args().skip(1).collect();
 
-    if let Some(result) = json["chart"]["result"][0].as_object() {
-        if let Some(meta) = result["meta"].as_object() {
-            let current_price = meta["regularMarketPrice"].as_f64().unwrap_or(0.0) as f32;
-            let prev_close = meta["chartPreviousClose"]
-                .as_f64()
-                .unwrap_or(current_price as f64) as f32;
+        let yahoo_base_url = config_value(&args, "--yahoo-url", "STOCK_TRACKER_YAHOO_URL")
+            .unwrap_or_else(|| DEFAULT_YAHOO_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
 
-            let change_percent = if prev_close != 0.0 {
-                ((current_price - prev_close) / prev_close) * 100.0
-            } else {
-                0.0
-            };
+        // Con --yahoo-url verso un mock la catena predefinita non ripiega su
+        // stooq.com: demo e test offline non devono toccare la rete
+        let default_providers = if yahoo_base_url == DEFAULT_YAHOO_URL {
+            DEFAULT_PROVIDERS
+        } else {
+            "yahoo"
+        };
+        let providers: Vec<String> = config_value(&args, "--provider", "STOCK_TRACKER_PROVIDER")
+            .unwrap_or_else(|| default_providers.to_string())
+            .split(',')
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
 
-            return Ok((current_price, change_percent));
-        }
-    }
+        let stooq_base_url = config_value(&args, "--stooq-url", "STOCK_TRACKER_STOOQ_URL")
+            .unwrap_or_else(|| DEFAULT_STOOQ_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
 
-    Err("Failed to parse stock data".into())
-}
+        let data_dir = config_value(&args, "--data-dir", "STOCK_TRACKER_DATA_DIR")
+            .unwrap_or_else(|| DEFAULT_DATA_DIR.to_string());
 
-fn fetch_24h_historical_data(
-    symbol: &str,
-) -> Result<(Vec<f32>, Vec<DateTime<Utc>>), Box<dyn std::error::Error>> {
-    let url = format!(
-        "https://query1.finance.yahoo.com/v8/finance/chart/{}?interval=5m&range=1d",
-        symbol
-    );
+        // "inf" e "NaN" sono f64 validi per parse ma non valori sensati
+        let number = |flag: &str, env_var: &str, default: f64| {
+            config_value(&args, flag, env_var)
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|v| v.is_finite())
+                .unwrap_or(default)
+        };
+        let sim = SimParams {
+            drift: number("--sim-drift", "STOCK_TRACKER_SIM_DRIFT", 0.05),
+            volatility: number("--sim-volatility", "STOCK_TRACKER_SIM_VOLATILITY", 0.30).abs(),
+            seed: number("--sim-seed", "STOCK_TRACKER_SIM_SEED", 42.0) as u64,
+            steps_per_quote: number("--sim-steps", "STOCK_TRACKER_SIM_STEPS", 60.0)
+                .clamp(1.0, 86_400.0) as u32,
+        };
+        let sim_symbols = number("--sim-symbols", "STOCK_TRACKER_SIM_SYMBOLS", 0.0) as usize;
 
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(&url)
-        .header("User-Agent", "Mozilla/5.0")
-        .timeout(Duration::from_secs(10))
-        .send()?;
+        let live_capacity = config_value(&args, "--live-points", "STOCK_TRACKER_LIVE_POINTS")
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|&n| n >= 2)
+            .unwrap_or(DEFAULT_LIVE_CAPACITY);
+
+        let watchlist_path = match config_value(&args, "--watchlist", "STOCK_TRACKER_WATCHLIST") {
+            Some(path) if path == "off" || path == "none" => None,
+            Some(path) => Some(path),
+            None => Some(DEFAULT_WATCHLIST_PATH.to_string()),
+        };
 
-    let json: serde_json::Value = response.json()?;
+        let tick_store_dir = match config_value(&args, "--tick-store", "STOCK_TRACKER_TICK_STORE") {
+            Some(dir) if dir == "off" || dir == "none" => None,
+            Some(dir) => Some(dir),
+            None => Some(DEFAULT_TICK_STORE_DIR.to_string()),
+        };
+        // Retention nulla o negativa svuoterebbe tutti i file alla compattazione
+        let retention_hours =
+            config_value(&args, "--retention-hours", "STOCK_TRACKER_RETENTION_HOURS")
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|&h| h.is_finite() && h > 0.0)
+                .unwrap_or(DEFAULT_RETENTION_HOURS)
+                .min(MAX_RETENTION_HOURS);
+        let tick_retention = chrono::Duration::minutes((retention_hours * 60.0).ceil() as i64);
 
-    if let Some(result) = json["chart"]["result"][0].as_object() {
-        let timestamps = result["timestamp"].as_array().ok_or("No timestamps")?;
-        let quotes = result["indicators"]["quote"][0]
-            .as_object()
-            .ok_or("No quotes")?;
-        let closes = quotes["close"].as_array().ok_or("No close prices")?;
+        let record_path = config_value(&args, "--record", "STOCK_TRACKER_RECORD");
+        let replay_path = config_value(&args, "--replay", "STOCK_TRACKER_REPLAY");
+        // Velocità enormi manderebbero in panic il clock del replay (elapsed × velocità)
+        let replay_speed =
+            number("--replay-speed", "STOCK_TRACKER_REPLAY_SPEED", 1.0).clamp(0.01, 1000.0);
 
-        let mut prices = Vec::new();
-        let mut times = Vec::new();
+        // In replay l'archivio tick e lo snapshot restano quelli della sessione reale
+        let tick_store_dir = tick_store_dir.filter(|_| replay_path.is_none());
+        let snapshot_path = match config_value(&args, "--snapshot", "STOCK_TRACKER_SNAPSHOT") {
+            _ if replay_path.is_some() => None,
+            Some(path) if path == "off" || path == "none" => None,
+            Some(path) => Some(path),
+            None => Some(DEFAULT_SNAPSHOT_PATH.to_string()),
+        };
+        let snapshot_interval = Duration::from_secs_f64(
+            number(
+                "--snapshot-interval",
+                "STOCK_TRACKER_SNAPSHOT_INTERVAL",
+                60.0,
+            )
+            .clamp(1.0, MAX_INTERVAL_SECS),
+        );
 
-        for (i, ts) in timestamps.iter().enumerate() {
-            if let (Some(timestamp), Some(price_val)) = (ts.as_i64(), closes.get(i)) {
-                if let Some(price) = price_val.as_f64() {
-                    let dt = DateTime::from_timestamp(timestamp, 0).unwrap_or(Utc::now());
-                    times.push(dt);
-                    prices.push(price as f32);
-                }
+        // Il replay accelerato deve interrogare i provider con la stessa frequenza
+        // "simulata" della registrazione, quindi gli intervalli si accorciano
+        let time_scale = if replay_path.is_some() {
+            replay_speed
+        } else {
+            1.0
+        };
+        let seconds = |flag: &str, env_var: &str, default: f64| {
+            Duration::from_secs_f64(
+                number(flag, env_var, default).clamp(1.0, MAX_INTERVAL_SECS) / time_scale,
+            )
+        };
+        // --poll-interval resta il default comune; ogni classe può avere il suo
+        let poll = number("--poll-interval", "STOCK_TRACKER_POLL_INTERVAL", 60.0);
+        let intervals = PollIntervals {
+            crypto: seconds(
+                "--crypto-interval",
+                "STOCK_TRACKER_CRYPTO_INTERVAL",
+                DEFAULT_CRYPTO_INTERVAL,
+            ),
+            fx: seconds("--fx-interval", "STOCK_TRACKER_FX_INTERVAL", poll),
+            index: seconds("--index-interval", "STOCK_TRACKER_INDEX_INTERVAL", poll),
+            equity: seconds("--equity-interval", "STOCK_TRACKER_EQUITY_INTERVAL", poll),
+            history: seconds(
+                "--history-interval",
+                "STOCK_TRACKER_HISTORY_INTERVAL",
+                DEFAULT_HISTORY_INTERVAL,
+            ),
+            offscreen_factor: number(
+                "--offscreen-factor",
+                "STOCK_TRACKER_OFFSCREEN_FACTOR",
+                DEFAULT_OFFSCREEN_FACTOR,
+            )
+            .clamp(1.0, 100.0),
+        };
+
+        // Di default lo stream Yahoo parte solo se Yahoo è nella catena e punta
+        // al server reale: con "sim", "file" o un mock locale mescolerebbe prezzi
+        // reali a quelli locali
+        // Registrazione e replay passano solo dai provider HTTP: con lo stream
+        // attivo in registrazione i simboli aggiornati dai tick non verrebbero
+        // interrogati, e in replay mancherebbero le loro risposte
+        let stream_url = match config_value(&args, "--stream-url", "STOCK_TRACKER_STREAM_URL") {
+            _ if replay_path.is_some() || record_path.is_some() => None,
+            Some(url) if url == "off" || url == "none" => None,
+            Some(url) => Some(url),
+            None if providers.iter().any(|p| p == "yahoo")
+                && yahoo_base_url == DEFAULT_YAHOO_URL =>
+            {
+                Some(DEFAULT_STREAM_URL.to_string())
             }
+            None => None,
+        };
+
+        let fetch_concurrency = config_value(&args, "--concurrency", "STOCK_TRACKER_CONCURRENCY")
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_FETCH_CONCURRENCY);
+
+        let rate_limit = config_value(&args, "--rate-limit", "STOCK_TRACKER_RATE_LIMIT")
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|&r| r > 0.0)
+            .unwrap_or(DEFAULT_RATE_LIMIT);
+
+        let rate_burst = config_value(&args, "--rate-burst", "STOCK_TRACKER_RATE_BURST")
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|&b| b >= 1.0)
+            .unwrap_or(DEFAULT_RATE_BURST);
+
+        let max_retries = config_value(&args, "--max-retries", "STOCK_TRACKER_MAX_RETRIES")
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MAX_RETRIES);
+
+        Self {
+            providers,
+            yahoo_base_url,
+            stooq_base_url,
+            data_dir,
+            sim,
+            sim_symbols,
+            watchlist_path,
+            symbols_file: config_value(&args, "--symbols-file", "STOCK_TRACKER_SYMBOLS_FILE"),
+            live_capacity,
+            tick_store_dir,
+            tick_retention,
+            snapshot_path,
+            snapshot_interval,
+            intervals,
+            holidays_path: config_value(&args, "--holidays", "STOCK_TRACKER_HOLIDAYS"),
+            base_currency: config_value(&args, "--base-currency", "STOCK_TRACKER_BASE_CURRENCY")
+                .map(|c| c.trim().to_uppercase())
+                .filter(|c| !c.is_empty()),
+            record_path,
+            replay_path,
+            replay_speed,
+            stream_url,
+            fetch_concurrency,
+            rate_limit,
+            rate_burst,
+            max_retries,
         }
+    }
+}
 
-        return Ok((prices, times));
+// Accetta sia "--flag valore" che "--flag=valore"
+fn config_value(args: &[String], flag: &str, env_var: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    for (i, arg) in args.iter().enumerate() {
+        if arg == flag {
+            if let Some(value) = args.get(i + 1) {
+                return Some(value.clone());
+            }
+        } else if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
     }
 
-    Err("Failed to parse historical data".into())
+    std::env::var(env_var).ok().filter(|v| !v.is_empty())
 }
 
 // ────────────────────────────────────────────────
-// Scrollbar (leggermente ottimizzata)
+// Provider dati di mercato
 // ────────────────────────────────────────────────
 
-fn draw_scrollbar(
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
-    scroll_offset: f32,
-    total_content_height: f32,
-    visible_height: f32,
-    scrollbar_state: &mut ScrollbarState,
-) -> f32 {
-    let scrollbar_width = 12.0;
-    let scrollbar_x = x + width - scrollbar_width - 5.0;
-    let scrollbar_y = y;
+type FetchResult<T> = Result<T, FetchError>;
 
-    draw_rectangle(
-        scrollbar_x,
-        scrollbar_y,
-        scrollbar_width,
-        height,
-        Color::from_rgba(20, 20, 25, 255),
-    );
-    draw_rectangle_lines(
-        scrollbar_x,
-        scrollbar_y,
-        scrollbar_width,
-        height,
-        1.0,
-        Color::from_rgba(40, 40, 50, 255),
-    );
+// Errori del layer dati, distinti per causa così la UI può spiegare
+// perché un titolo non si aggiorna.
+#[derive(Debug, Clone)]
+enum FetchError {
+    Timeout,
+    Network(String),
+    // 404 = ticker sconosciuto, 429 = rate limit (con eventuale Retry-After)
+    Http {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    // Payload `chart.error` restituito da Yahoo
+    Provider {
+        code: String,
+        description: String,
+    },
+    Parse(String),
+    // Lettura/scrittura di file locali
+    Io(String),
+}
 
-    let max_scroll = (total_content_height - visible_height).max(0.0);
-    if max_scroll <= 0.0 {
-        return scroll_offset;
+impl FetchError {
+    // Errori transitori: ha senso ripetere la richiesta
+    fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Timeout | FetchError::Network(_) => true,
+            FetchError::Http { status, .. } => *status == 429 || *status >= 500,
+            FetchError::Provider { .. } | FetchError::Parse(_) | FetchError::Io(_) => false,
+        }
     }
 
-    let thumb_height = ((visible_height / total_content_height) * height)
-        .max(30.0)
-        .min(height - 10.0);
-    let scroll_range = height - thumb_height;
-    let thumb_y = scrollbar_y + (scroll_offset / max_scroll) * scroll_range;
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::Http {
+                status: 429,
+                retry_after,
+            } => Some(retry_after.unwrap_or(Duration::from_secs(30))),
+            _ => None,
+        }
+    }
 
-    let (mx, my) = mouse_position();
-    let is_on_scrollbar = mx >= scrollbar_x
-        && mx <= scrollbar_x + scrollbar_width
-        && my >= scrollbar_y
-        && my <= scrollbar_y + height;
-    let is_on_thumb = is_on_scrollbar && my >= thumb_y && my <= thumb_y + thumb_height;
+    // Testo breve per la lista titoli
+    fn short_label(&self) -> String {
+        match self {
+            FetchError::Timeout => "Timeout".to_string(),
+            FetchError::Network(_) => "Errore di rete".to_string(),
+            FetchError::Http { status: 404, .. } => "Simbolo non trovato".to_string(),
+            FetchError::Http { status: 429, .. } => "Troppe richieste (429)".to_string(),
+            FetchError::Http { status, .. } => format!("HTTP {}", status),
+            FetchError::Provider { code, .. } => format!("Errore provider: {}", code),
+            FetchError::Parse(_) => "Risposta non valida".to_string(),
+            FetchError::Io(_) => "Errore file".to_string(),
+        }
+    }
+}
 
-    let thumb_color = if scrollbar_state.dragging {
-        Color::from_rgba(120, 120, 140, 255)
-    } else if is_on_thumb {
-        Color::from_rgba(100, 100, 120, 255)
-    } else if is_on_scrollbar {
-        Color::from_rgba(80, 80, 100, 255)
-    } else {
-        Color::from_rgba(60, 60, 80, 255)
-    };
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Timeout => write!(f, "timeout della richiesta"),
+            FetchError::Network(msg) => write!(f, "errore di rete: {}", msg),
+            FetchError::Http {
+                status,
+                retry_after: Some(wait),
+            } => write!(f, "HTTP {} (riprovare tra {}s)", status, wait.as_secs()),
+            FetchError::Http { status, .. } => write!(f, "HTTP {}", status),
+            FetchError::Provider { code, description } => write!(f, "{}: {}", code, description),
+            FetchError::Parse(msg) => write!(f, "parsing fallito: {}", msg),
+            FetchError::Io(msg) => write!(f, "errore file: {}", msg),
+        }
+    }
+}
 
-    draw_rectangle(
-        scrollbar_x + 2.0,
-        thumb_y + 2.0,
-        scrollbar_width - 4.0,
-        thumb_height - 4.0,
-        thumb_color,
-    );
+impl std::error::Error for FetchError {}
 
-    // tre linee sul thumb
-    let indicator_y = thumb_y + thumb_height / 2.0;
-    for i in 0..3 {
-        let ly = indicator_y - 2.0 + (i as f32 * 2.0);
-        draw_line(
-            scrollbar_x + 4.0,
-            ly,
-            scrollbar_x + scrollbar_width - 4.0,
-            ly,
-            1.0,
-            Color::from_rgba(40, 40, 50, 200),
-        );
+impl From<reqwest::Error> for FetchError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            FetchError::Timeout
+        } else if let Some(status) = err.status() {
+            FetchError::Http {
+                status: status.as_u16(),
+                retry_after: None,
+            }
+        } else if err.is_decode() {
+            FetchError::Parse(err.to_string())
+        } else {
+            FetchError::Network(err.to_string())
+        }
     }
+}
 
-    if is_mouse_button_pressed(MouseButton::Left) && is_on_thumb {
-        scrollbar_state.dragging = true;
-        scrollbar_state.drag_start_y = my;
-        scrollbar_state.drag_start_scroll = scroll_offset;
+impl From<tungstenite::Error> for FetchError {
+    fn from(err: tungstenite::Error) -> Self {
+        match err {
+            tungstenite::Error::Io(io)
+                if matches!(
+                    io.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                FetchError::Timeout
+            }
+            tungstenite::Error::Http(response) => FetchError::Http {
+                status: response.status().as_u16(),
+                retry_after: None,
+            },
+            other => FetchError::Network(other.to_string()),
+        }
     }
+}
 
-    if is_mouse_button_released(MouseButton::Left) {
-        scrollbar_state.dragging = false;
+impl From<ChartError> for FetchError {
+    fn from(err: ChartError) -> Self {
+        FetchError::Provider {
+            code: err.code,
+            description: err.description,
+        }
     }
+}
 
-    if scrollbar_state.dragging {
-        let delta_y = my - scrollbar_state.drag_start_y;
-        let scroll_delta = (delta_y / scroll_range) * max_scroll;
-        let new_scroll = (scrollbar_state.drag_start_scroll + scroll_delta).clamp(0.0, max_scroll);
-        return new_scroll;
+impl From<std::io::Error> for FetchError {
+    fn from(err: std::io::Error) -> Self {
+        FetchError::Io(err.to_string())
     }
+}
 
-    // click sulla track
-    if is_mouse_button_pressed(MouseButton::Left) && is_on_scrollbar && !is_on_thumb {
-        let click_ratio = (my - scrollbar_y) / height;
-        let new_scroll = (click_ratio * max_scroll).clamp(0.0, max_scroll);
-        return new_scroll;
+impl From<serde_json::Error> for FetchError {
+    fn from(err: serde_json::Error) -> Self {
+        FetchError::Parse(err.to_string())
     }
-
-    scroll_offset
 }
 
-// ────────────────────────────────────────────────
-// Item lista con checkbox (invariato, ma chiamato meno volte)
-// ────────────────────────────────────────────────
+type History = Vec<Candle>;
 
-fn draw_list_item(
-    stock: &StockData,
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
-    is_selected: bool,
-    mouse_pos: (f32, f32),
-) -> bool {
-    let (mx, my) = mouse_pos;
-    let is_hovered = mx >= x && mx <= x + width && my >= y && my <= y + height;
-    let is_clicked = is_hovered && is_mouse_button_pressed(MouseButton::Left);
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Candle {
+    time: DateTime<Utc>,
+    open: f32,
+    high: f32,
+    low: f32,
+    close: f32,
+    volume: u64,
+}
 
-    let bg_color = if is_hovered {
-        Color::from_rgba(40, 40, 50, 255)
-    } else {
-        Color::from_rgba(30, 30, 40, 255)
-    };
-
-    draw_rectangle(x, y, width, height, bg_color);
-    draw_rectangle_lines(x, y, width, height, 1.0, Color::from_rgba(50, 50, 60, 255));
+#[derive(Debug, Clone)]
+struct Quote {
+    price: f32,
+    // Variazione % rispetto alla chiusura precedente
+    change_percent: f32,
+    // Orario dell'ultimo scambio: uguale al precedente = mercato fermo
+    market_time: Option<DateTime<Utc>>,
+    session: Option<TradingSession>,
+    // Ultimo prezzo fuori seduta regolare, se la borsa è in pre/post-market
+    extended: Option<ExtendedQuote>,
+    currency: Option<String>,
+    price_hint: Option<u32>,
+}
 
-    // Checkbox
-    let cs = 20.0;
-    let cx = x + 10.0;
-    let cy = y + (height - cs) / 2.0;
+#[derive(Debug, Clone)]
+struct ExtendedQuote {
+    // MarketStatus::Pre o MarketStatus::Post
+    phase: MarketStatus,
+    price: f32,
+    // Pre-market rispetto alla chiusura precedente, post-market rispetto
+    // alla chiusura della seduta regolare
+    change_percent: f32,
+}
 
-    let checkbox_color = if is_selected {
-        Color::from_rgba(50, 100, 200, 255)
-    } else {
-        Color::from_rgba(40, 40, 50, 255)
-    };
-    draw_rectangle(cx, cy, cs, cs, checkbox_color);
-    draw_rectangle_lines(cx, cy, cs, cs, 2.0, Color::from_rgba(100, 100, 120, 255));
+impl ExtendedQuote {
+    fn from_candles(
+        candles: &[Candle],
+        session: &TradingSession,
+        meta: &ChartMeta,
+    ) -> Option<Self> {
+        let last = candles.last()?;
+        let phase = session.phase_at(last.time);
+        let reference = match phase {
+            MarketStatus::Pre => meta.chart_previous_close.or(meta.previous_close)?,
+            MarketStatus::Post => meta.regular_market_price,
+            MarketStatus::Open | MarketStatus::Closed => return None,
+        } as f32;
 
-    if is_selected {
-        draw_line(cx + 4.0, cy + 10.0, cx + 8.0, cy + 16.0, 2.0, WHITE);
-        draw_line(cx + 8.0, cy + 16.0, cx + 16.0, cy + 4.0, 2.0, WHITE);
+        let change_percent = if reference != 0.0 {
+            ((last.close - reference) / reference) * 100.0
+        } else {
+            0.0
+        };
+        Some(Self {
+            phase,
+            price: last.close,
+            change_percent,
+        })
     }
+}
 
-    draw_text(&stock.symbol, x + 45.0, y + 22.0, 20.0, WHITE);
-
-    if stock.current_price > 0.0 {
-        draw_text(
-            &format!("${:.2}", stock.current_price),
-            x + 45.0,
-            y + 42.0,
-            20.0,
-            LIGHTGRAY,
-        );
+impl Quote {
+    fn from_meta(meta: &ChartMeta) -> Self {
+        let price = meta.regular_market_price as f32;
+        let prev_close = meta
+            .chart_previous_close
+            .or(meta.previous_close)
+            .unwrap_or(meta.regular_market_price) as f32;
 
-        let ch_color = if stock.change_percent >= 0.0 {
-            Color::from_rgba(0, 200, 100, 255)
+        let change_percent = if prev_close != 0.0 {
+            ((price - prev_close) / prev_close) * 100.0
         } else {
-            Color::from_rgba(220, 50, 50, 255)
+            0.0
         };
-        draw_text(
-            &format!("{:+.2}%", stock.change_percent),
-            x + 45.0,
-            y + 60.0,
-            16.0,
-            ch_color,
-        );
 
-        if stock.change_24h_percent != 0.0 {
-            draw_text(
-                &format!("24h: {:+.2}%", stock.change_24h_percent),
-                x + width - 90.0,
-                y + 60.0,
-                16.0,
-                Color::from_rgba(100, 150, 255, 255),
-            );
+        Self {
+            price,
+            change_percent,
+            market_time: meta
+                .regular_market_time
+                .and_then(|t| DateTime::from_timestamp(t, 0)),
+            session: TradingSession::from_meta(meta),
+            extended: None,
+            currency: meta.currency.clone(),
+            price_hint: meta.price_hint,
         }
-    } else {
-        draw_text("Caricamento...", x + 45.0, y + 48.0, 16.0, GRAY);
     }
 
-    is_clicked
+    // Chart richiesto con includePrePost=true: l'ultima candela, se cade
+    // fuori dalla seduta regolare, dà il prezzo pre/post-market
+    fn from_chart(result: &ChartResult) -> Self {
+        let mut quote = Self::from_meta(&result.meta);
+        if let Some(session) = &quote.session
+            && let Ok(candles) = candles_from_chart(result)
+        {
+            quote.extended = ExtendedQuote::from_candles(&candles, session, &result.meta);
+        }
+        quote
+    }
 }
 
-// ────────────────────────────────────────────────
-// Pannello lista sinistra – SOLO ITEM VISIBILI
-// ────────────────────────────────────────────────
+// Intervalli selezionabili per il grafico, con la granularità usata per ciascuno
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+enum ChartRange {
+    #[default]
+    Day1,
+    Day5,
+    Month1,
+    Month6,
+    Year1,
+    Year5,
+    Max,
+}
 
-fn draw_list_panel(
-    stocks: &HashMap<String, StockData>,
-    symbols: &[String],
-    selected_symbols: &HashSet<String>,
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
-    scroll_offset: f32,
-    scrollbar_state: &mut ScrollbarState,
-) -> (Option<String>, f32) {
-    draw_rectangle(x, y, width, height, Color::from_rgba(25, 25, 35, 255));
+impl ChartRange {
+    const ALL: [ChartRange; 7] = [
+        ChartRange::Day1,
+        ChartRange::Day5,
+        ChartRange::Month1,
+        ChartRange::Month6,
+        ChartRange::Year1,
+        ChartRange::Year5,
+        ChartRange::Max,
+    ];
 
-    draw_text("TITOLI", x + 10.0, y + 30.0, 24.0, WHITE);
-    draw_text(
-        &format!("Selezionati: {}", selected_symbols.len()),
-        x + width - 130.0,
-        y + 30.0,
-        18.0,
-        LIGHTGRAY,
-    );
+    fn label(self) -> &'static str {
+        match self {
+            ChartRange::Day1 => "1D",
+            ChartRange::Day5 => "5D",
+            ChartRange::Month1 => "1M",
+            ChartRange::Month6 => "6M",
+            ChartRange::Year1 => "1Y",
+            ChartRange::Year5 => "5Y",
+            ChartRange::Max => "MAX",
+        }
+    }
 
-    draw_line(
-        x,
-        y + 40.0,
-        x + width,
-        y + 40.0,
-        2.0,
-        Color::from_rgba(50, 50, 60, 255),
-    );
+    // Finestra temporale coperta (None = tutto lo storico)
+    fn span(self) -> Option<chrono::Duration> {
+        match self {
+            ChartRange::Day1 => Some(chrono::Duration::days(1)),
+            ChartRange::Day5 => Some(chrono::Duration::days(5)),
+            ChartRange::Month1 => Some(chrono::Duration::days(30)),
+            ChartRange::Month6 => Some(chrono::Duration::days(182)),
+            ChartRange::Year1 => Some(chrono::Duration::days(365)),
+            ChartRange::Year5 => Some(chrono::Duration::days(5 * 365)),
+            ChartRange::Max => None,
+        }
+    }
 
-    let item_height = 75.0;
-    let item_padding = 5.0;
-    let item_total_h = item_height + item_padding;
-    let start_y = y + 50.0;
-    let visible_h = height - 50.0;
-    let mouse_pos = mouse_position();
+    // Durata di una candela (corrisponde all'interval di yahoo_params)
+    fn candle_duration(self) -> chrono::Duration {
+        match self {
+            ChartRange::Day1 => chrono::Duration::minutes(5),
+            ChartRange::Day5 => chrono::Duration::minutes(15),
+            ChartRange::Month1 => chrono::Duration::hours(1),
+            ChartRange::Month6 | ChartRange::Year1 => chrono::Duration::days(1),
+            ChartRange::Year5 => chrono::Duration::weeks(1),
+            ChartRange::Max => chrono::Duration::days(30),
+        }
+    }
 
-    // Solo items visibili
-    let first_idx = (scroll_offset / item_total_h).floor().max(0.0) as usize;
-    let last_idx =
-        (((scroll_offset + visible_h) / item_total_h).ceil() as usize).min(symbols.len());
+    // Candele sotto il giorno: hanno senso le sedute pre/post-market
+    fn is_intraday(self) -> bool {
+        matches!(self, ChartRange::Day1 | ChartRange::Day5)
+    }
 
-    let mut clicked_symbol = None;
-    let mut new_scroll = scroll_offset;
+    // (range, interval) come li vuole Yahoo
+    fn yahoo_params(self) -> (&'static str, &'static str) {
+        match self {
+            ChartRange::Day1 => ("1d", "5m"),
+            ChartRange::Day5 => ("5d", "15m"),
+            ChartRange::Month1 => ("1mo", "1h"),
+            ChartRange::Month6 => ("6mo", "1d"),
+            ChartRange::Year1 => ("1y", "1d"),
+            ChartRange::Year5 => ("5y", "1wk"),
+            ChartRange::Max => ("max", "1mo"),
+        }
+    }
+}
 
-    // Mouse wheel
-    let (_wx, wy) = mouse_wheel();
-    let mouse_in_list = mouse_pos.0 >= x
-        && mouse_pos.0 <= x + width
-        && mouse_pos.1 >= y
-        && mouse_pos.1 <= y + height;
-    if mouse_in_list && !scrollbar_state.dragging {
-        new_scroll -= wy * 60.0; // invertito per feeling naturale
-        let max_scroll = (symbols.len() as f32 * item_total_h - visible_h).max(0.0);
-        new_scroll = new_scroll.clamp(0.0, max_scroll);
+// I worker dipendono solo da questo trait: per cambiare sorgente dati
+// (o usarne una finta) basta passare un'altra implementazione.
+trait MarketDataProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn fetch_quote(&self, symbol: &str) -> FetchResult<Quote>;
+
+    // true se fetch_quotes usa davvero un endpoint multi-simbolo;
+    // altrimenti i worker fanno le singole richieste in parallelo.
+    fn supports_batch(&self) -> bool {
+        false
     }
 
-    let items_width = width - 30.0;
+    // Quote di più simboli insieme. Di default una richiesta per simbolo,
+    // al massimo `concurrency` alla volta: i provider con un endpoint
+    // multi-simbolo la sovrascrivono.
+    fn fetch_quotes(
+        &self,
+        symbols: &[String],
+        concurrency: usize,
+    ) -> Vec<(String, FetchResult<Quote>)> {
+        fetch_each(self, symbols, concurrency)
+    }
 
-    for i in first_idx..last_idx {
-        let symbol = &symbols[i];
-        if let Some(stock) = stocks.get(symbol) {
-            let item_y = start_y + (i as f32 * item_total_h) - new_scroll;
+    // Storico in candele OHLCV per l'intervallo richiesto
+    fn fetch_history(&self, symbol: &str, range: ChartRange) -> FetchResult<History>;
 
-            if draw_list_item(
-                stock,
-                x + 10.0,
-                item_y,
-                items_width - 20.0,
-                item_height,
-                selected_symbols.contains(symbol),
-                mouse_pos,
-            ) {
-                clicked_symbol = Some(symbol.clone());
+    // Strumenti che corrispondono a un ticker o a un nome (casella di ricerca)
+    fn search_symbols(&self, _query: &str) -> FetchResult<Vec<SymbolMatch>> {
+        Err(FetchError::Provider {
+            code: "Unsupported".to_string(),
+            description: format!("ricerca non disponibile con {}", self.name()),
+        })
+    }
+}
+
+// Quote singole in parallelo, con la concorrenza limitata di for_each_bounded
+fn fetch_each<P: MarketDataProvider + ?Sized>(
+    provider: &P,
+    symbols: &[String],
+    concurrency: usize,
+) -> Vec<(String, FetchResult<Quote>)> {
+    let results = Mutex::new(Vec::with_capacity(symbols.len()));
+    for_each_bounded(symbols, concurrency, |symbol| {
+        let result = provider.fetch_quote(symbol);
+        if let Ok(mut results) = results.lock() {
+            results.push((symbol.clone(), result));
+        }
+    });
+    results.into_inner().unwrap_or_default()
+}
+
+// Massimo numero di simboli accettato da /v7/finance/spark per richiesta
+const YAHOO_BATCH_SIZE: usize = 20;
+
+struct YahooProvider {
+    base_url: String,
+    http: HttpClient,
+    // false dopo un 404 di /v7/finance/spark (es. mock con il solo /v8/finance/chart)
+    spark_available: AtomicBool,
+}
+
+impl YahooProvider {
+    fn new(base_url: &str, http: HttpClient) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            http,
+            spark_available: AtomicBool::new(true),
+        }
+    }
+}
+
+impl MarketDataProvider for YahooProvider {
+    fn name(&self) -> &str {
+        "yahoo"
+    }
+
+    fn fetch_quote(&self, symbol: &str) -> FetchResult<Quote> {
+        fetch_stock_data(&self.http, &self.base_url, symbol)
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    // Se il batch fallisce senza speranza di riuscire ripetendolo (endpoint
+    // assente, risposta non valida) si ripiega sul chart per simbolo
+    fn fetch_quotes(
+        &self,
+        symbols: &[String],
+        concurrency: usize,
+    ) -> Vec<(String, FetchResult<Quote>)> {
+        let mut out = Vec::with_capacity(symbols.len());
+        for chunk in symbols.chunks(YAHOO_BATCH_SIZE) {
+            if !self.spark_available.load(Ordering::Relaxed) {
+                out.extend(fetch_each(self, chunk, concurrency));
+                continue;
+            }
+            match fetch_quote_batch(&self.http, &self.base_url, chunk) {
+                Ok(results) => out.extend(results),
+                Err(err) if !err.is_retryable() => {
+                    if matches!(err, FetchError::Http { status: 404, .. }) {
+                        self.spark_available.store(false, Ordering::Relaxed);
+                    }
+                    out.extend(fetch_each(self, chunk, concurrency));
+                }
+                // Richiesta fallita: lo stesso errore vale per tutti i simboli del blocco
+                Err(err) => out.extend(
+                    chunk
+                        .iter()
+                        .map(|symbol| (symbol.clone(), Err(err.clone()))),
+                ),
             }
         }
+        out
     }
 
-    // Scrollbar
-    let total_content_h = symbols.len() as f32 * item_total_h;
-    new_scroll = draw_scrollbar(
-        x,
-        start_y,
-        width,
-        visible_h,
-        new_scroll,
-        total_content_h,
-        visible_h,
-        scrollbar_state,
+    fn fetch_history(&self, symbol: &str, range: ChartRange) -> FetchResult<History> {
+        fetch_24h_historical_data(&self.http, &self.base_url, symbol, range)
+    }
+
+    fn search_symbols(&self, query: &str) -> FetchResult<Vec<SymbolMatch>> {
+        search_yahoo_symbols(&self.http, &self.base_url, query)
+    }
+}
+
+// `include_pre_post` aggiunge le candele di pre e post-market (solo intraday)
+fn fetch_chart(
+    http: &HttpClient,
+    base_url: &str,
+    symbol: &str,
+    interval: &str,
+    range: &str,
+    include_pre_post: bool,
+) -> FetchResult<ChartResult> {
+    let url = format!(
+        "{}/v8/finance/chart/{}?interval={}&range={}&includePrePost={}",
+        base_url, symbol, interval, range, include_pre_post
     );
 
-    (clicked_symbol, new_scroll)
+    let body = http.get_text(&url)?;
+    parse_chart_response(&body)
 }
 
-// ────────────────────────────────────────────────
-// Mini-grafico – usa valori precalcolati
-// ────────────────────────────────────────────────
+fn parse_chart_response(body: &str) -> FetchResult<ChartResult> {
+    let response: ChartResponse = serde_json::from_str(body)?;
 
-fn draw_mini_chart(stock: &StockData, x: f32, y: f32, width: f32, height: f32) {
-    draw_rectangle(x, y, width, height, Color::from_rgba(30, 30, 40, 255));
-    draw_rectangle_lines(x, y, width, height, 2.0, Color::from_rgba(50, 50, 60, 255));
+    if let Some(err) = response.chart.error {
+        return Err(err.into());
+    }
 
-    draw_text(&stock.symbol, x + 15.0, y + 28.0, 24.0, WHITE);
-    draw_text(
-        &format!("${:.2}", stock.current_price),
-        x + 15.0,
-        y + 52.0,
-        20.0,
-        LIGHTGRAY,
-    );
+    response
+        .chart
+        .result
+        .and_then(|results| results.into_iter().next())
+        .ok_or_else(|| FetchError::Parse("risposta senza result".to_string()))
+}
 
-    let ch_color = if stock.change_percent >= 0.0 {
-        Color::from_rgba(0, 200, 100, 255)
-    } else {
-        Color::from_rgba(220, 50, 50, 255)
-    };
-    draw_text(
-        &format!("{:+.2}%", stock.change_percent),
-        x + width - 90.0,
-        y + 32.0,
-        20.0,
-        ch_color,
+// Una sola richiesta /v7/finance/spark per tutto il blocco di simboli
+fn fetch_quote_batch(
+    http: &HttpClient,
+    base_url: &str,
+    symbols: &[String],
+) -> FetchResult<Vec<(String, FetchResult<Quote>)>> {
+    let url = format!(
+        "{}/v7/finance/spark?symbols={}&interval=5m&range=1d&includePrePost=true",
+        base_url,
+        symbols.join(",")
     );
 
-    if stock.change_24h_percent != 0.0 {
-        draw_text(
-            &format!("24h: {:+.2}%", stock.change_24h_percent),
-            x + width - 90.0,
-            y + 52.0,
-            16.0,
-            Color::from_rgba(100, 150, 255, 255),
-        );
-    }
+    let mut results = parse_spark_response(&http.get_text(&url)?)?;
+    Ok(symbols
+        .iter()
+        .map(|symbol| {
+            let quote = results.remove(symbol).unwrap_or_else(|| {
+                Err(FetchError::Provider {
+                    code: "Not Found".to_string(),
+                    description: format!("{} assente nella risposta batch", symbol),
+                })
+            });
+            (symbol.clone(), quote)
+        })
+        .collect())
+}
 
-    if stock.prices.len() < 2 {
-        draw_text(
-            "Caricamento...",
-            x + width / 2.0 - 50.0,
-            y + height / 2.0 + 20.0,
-            16.0,
-            GRAY,
-        );
-        return;
+fn parse_spark_response(body: &str) -> FetchResult<HashMap<String, FetchResult<Quote>>> {
+    let response: SparkResponse = serde_json::from_str(body)?;
+
+    if let Some(err) = response.spark.error {
+        return Err(err.into());
     }
 
-    let chart_x = x + 70.0;
-    let chart_y = y + 75.0;
-    let chart_w = width - 90.0;
-    let chart_h = height - 105.0;
+    Ok(response
+        .spark
+        .result
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| {
+            let quote = item
+                .response
+                .first()
+                .map(Quote::from_chart)
+                .ok_or_else(|| FetchError::Parse("risposta spark vuota".to_string()));
+            (item.symbol, quote)
+        })
+        .collect())
+}
 
-    // ── Layer 24h (blu) ───────────────────────────────────────
-    if stock.prices_24h_ago.len() >= 2 && stock.h24_max > stock.h24_min {
-        let range = stock.h24_max - stock.h24_min;
-        let padding = range * 0.08;
-        let min_val = stock.h24_min - padding;
-        let max_val = stock.h24_max + padding;
-        let val_range = max_val - min_val;
+fn fetch_stock_data(http: &HttpClient, base_url: &str, symbol: &str) -> FetchResult<Quote> {
+    let result = fetch_chart(http, base_url, symbol, "1m", "1d", true)?;
+    Ok(Quote::from_chart(&result))
+}
 
-        let blue = Color::from_rgba(100, 150, 255, 150);
-        let mut fill_blue = blue;
-        fill_blue.a = 0.1;
+fn fetch_24h_historical_data(
+    http: &HttpClient,
+    base_url: &str,
+    symbol: &str,
+    range: ChartRange,
+) -> FetchResult<History> {
+    let intraday = range.is_intraday();
+    let (range, interval) = range.yahoo_params();
+    let result = fetch_chart(http, base_url, symbol, interval, range, intraday)?;
+    candles_from_chart(&result)
+}
 
-        for i in 0..stock.prices_24h_ago.len() - 1 {
-            let x1 = chart_x + (i as f32 / (stock.prices_24h_ago.len() - 1) as f32) * chart_w;
-            let y1 =
-                chart_y + chart_h - ((stock.prices_24h_ago[i] - min_val) / val_range) * chart_h;
-            let x2 = chart_x + ((i + 1) as f32 / (stock.prices_24h_ago.len() - 1) as f32) * chart_w;
-            let y2 =
-                chart_y + chart_h - ((stock.prices_24h_ago[i + 1] - min_val) / val_range) * chart_h;
+// /v1/finance/search: solo gli strumenti, niente notizie
+fn search_yahoo_symbols(
+    http: &HttpClient,
+    base_url: &str,
+    query: &str,
+) -> FetchResult<Vec<SymbolMatch>> {
+    let url = reqwest::Url::parse_with_params(
+        &format!("{}/v1/finance/search", base_url),
+        &[
+            ("q", query),
+            ("quotesCount", &SEARCH_MAX_RESULTS.to_string()),
+            ("newsCount", "0"),
+        ],
+    )
+    .map_err(|err| FetchError::Parse(err.to_string()))?;
 
-            draw_triangle(
-                vec2(x1, y1),
-                vec2(x2, y2),
-                vec2(x2, chart_y + chart_h),
-                fill_blue,
-            );
-            draw_triangle(
-                vec2(x1, y1),
-                vec2(x1, chart_y + chart_h),
-                vec2(x2, chart_y + chart_h),
-                fill_blue,
-            );
-            draw_line(x1, y1, x2, y2, 2.2, blue);
-        }
+    let body = http.get_text(url.as_str())?;
+    let response: SearchResponse = serde_json::from_str(&body)?;
+    Ok(response
+        .quotes
+        .into_iter()
+        .filter_map(SymbolMatch::from_yahoo)
+        .collect())
+}
 
-        // etichette sinistra (blu)
-        for i in 0..3 {
-            let frac = i as f32 / 2.0;
-            let price = max_val - frac * val_range;
-            let gy = chart_y + frac * chart_h;
-            draw_text(
-                &format!("${:.2}", price),
-                x + 5.0,
-                gy + 5.0,
-                13.0,
-                Color::from_rgba(100, 150, 255, 180),
-            );
+fn candles_from_chart(result: &ChartResult) -> FetchResult<Vec<Candle>> {
+    let quote = result
+        .indicators
+        .quote
+        .first()
+        .ok_or_else(|| FetchError::Parse("nessuna serie quote".to_string()))?;
+
+    let mut candles = Vec::with_capacity(result.timestamp.len());
+
+    // Le candele senza scambi arrivano come null: vengono saltate
+    for (i, &timestamp) in result.timestamp.iter().enumerate() {
+        let value = |series: &[Option<f64>]| series.get(i).copied().flatten();
+
+        if let Some(close) = value(&quote.close)
+            && let Some(time) = DateTime::from_timestamp(timestamp, 0)
+        {
+            candles.push(Candle {
+                time,
+                open: value(&quote.open).unwrap_or(close) as f32,
+                high: value(&quote.high).unwrap_or(close) as f32,
+                low: value(&quote.low).unwrap_or(close) as f32,
+                close: close as f32,
+                volume: quote.volume.get(i).copied().flatten().unwrap_or(0),
+            });
         }
     }
 
-    // ── Layer corrente (verde/rosso) ───────────────────────────
-    if stock.current_max > stock.current_min {
-        let range = stock.current_max - stock.current_min;
-        let padding = range * 0.08;
-        let min_val = stock.current_min - padding;
-        let max_val = stock.current_max + padding;
-        let val_range = max_val - min_val;
+    Ok(candles)
+}
+
+// ────────────────────────────────────────────────
+// Provider Stooq (CSV giornaliero) e catena di fallback
+// ────────────────────────────────────────────────
+
+struct StooqProvider {
+    base_url: String,
+    http: HttpClient,
+}
+
+impl StooqProvider {
+    fn new(base_url: &str, http: HttpClient) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            http,
+        }
+    }
+
+    fn fetch_csv(
+        &self,
+        symbol: &str,
+        interval: &str,
+        from: Option<NaiveDate>,
+    ) -> FetchResult<Vec<Candle>> {
+        let mut url = format!(
+            "{}/q/d/l/?s={}&i={}",
+            self.base_url,
+            stooq_symbol(symbol)?,
+            interval
+        );
+        if let Some(from) = from {
+            url.push_str(&format!("&d1={}", from.format("%Y%m%d")));
+        }
+
+        let body = self.http.get_text(&url)?;
+        parse_stooq_csv(&body)
+    }
+}
+
+impl MarketDataProvider for StooqProvider {
+    fn name(&self) -> &str {
+        "stooq"
+    }
+
+    // Solo dati di fine giornata: ultima chiusura rispetto alla precedente
+    fn fetch_quote(&self, symbol: &str) -> FetchResult<Quote> {
+        let from = self.http.tape.now().date_naive() - chrono::Duration::days(14);
+        let candles = self.fetch_csv(symbol, "d", Some(from))?;
+        quote_from_candles(&candles)
+    }
+
+    fn fetch_history(&self, symbol: &str, range: ChartRange) -> FetchResult<History> {
+        let interval = match range {
+            ChartRange::Year5 => "w",
+            ChartRange::Max => "m",
+            _ => "d",
+        };
+        let from = range
+            .span()
+            .map(|span| (self.http.tape.now() - span).date_naive() - chrono::Duration::days(7));
+        let mut candles = self.fetch_csv(symbol, interval, from)?;
+        trim_to_range(&mut candles, range);
+        Ok(candles)
+    }
+}
+
+// Quote dall'ultima candela rispetto alla chiusura precedente
+fn quote_from_candles(candles: &[Candle]) -> FetchResult<Quote> {
+    let last = candles
+        .last()
+        .ok_or_else(|| FetchError::Parse("serie senza candele".to_string()))?;
+    let prev_close = candles
+        .len()
+        .checked_sub(2)
+        .map(|i| candles[i].close)
+        .unwrap_or(last.open);
+
+    let change_percent = if prev_close != 0.0 {
+        ((last.close - prev_close) / prev_close) * 100.0
+    } else {
+        0.0
+    };
+
+    Ok(Quote {
+        price: last.close,
+        change_percent,
+        market_time: Some(last.time),
+        session: None,
+        extended: None,
+        currency: None,
+        price_hint: None,
+    })
+}
+
+// Tiene solo le candele dentro l'intervallo, contato dall'ultima disponibile.
+// Con candele giornaliere 1D/5D sarebbero quasi vuoti: almeno 2 punti restano.
+fn trim_to_range(candles: &mut Vec<Candle>, range: ChartRange) {
+    if let Some(span) = range.span()
+        && let Some(last) = candles.last()
+    {
+        let cutoff = last.time - span;
+        let keep = candles.iter().filter(|c| c.time >= cutoff).count().max(2);
+        let skip = candles.len().saturating_sub(keep);
+        candles.drain(..skip);
+    }
+}
+
+// Indici Yahoo → codici Stooq: i nomi non coincidono quasi mai
+const STOOQ_INDICES: [(&str, &str); 7] = [
+    ("^GSPC", "^spx"),
+    ("^DJI", "^dji"),
+    ("^IXIC", "^ndq"),
+    ("^NDX", "^ndx"),
+    ("^FTSE", "^ukx"),
+    ("^GDAXI", "^dax"),
+    ("^N225", "^nkx"),
+];
+
+// AAPL → aapl.us, BRK-B → brk-b.us, BTC-USD → btcusd, EURUSD=X → eurusd,
+// VOD.L → vod.uk, ^GSPC → ^spx
+fn stooq_symbol(symbol: &str) -> FetchResult<String> {
+    let lower = symbol.to_lowercase();
+
+    if let Some(pair) = lower.strip_suffix("=x") {
+        return Ok(pair.to_string());
+    }
+    if AssetClass::of(symbol) == AssetClass::Crypto {
+        return Ok(lower.replace('-', ""));
+    }
+    if lower.starts_with('^') {
+        return STOOQ_INDICES
+            .iter()
+            .find(|(yahoo, _)| yahoo.eq_ignore_ascii_case(symbol))
+            .map(|(_, stooq)| stooq.to_string())
+            .ok_or_else(|| FetchError::Provider {
+                code: "Unsupported".to_string(),
+                description: format!("indice {} senza corrispondente Stooq", symbol),
+            });
+    }
+    if let Some((ticker, suffix)) = lower.rsplit_once('.') {
+        let market = match suffix {
+            "l" => "uk",
+            "de" | "f" => "de",
+            "t" => "jp",
+            "hk" => "hk",
+            other => other,
+        };
+        return Ok(format!("{}.{}", ticker, market));
+    }
+
+    Ok(format!("{}.us", lower))
+}
+
+// CSV stile Stooq: Date,Open,High,Low,Close[,Volume] (Volume assente su FX/indici)
+fn parse_stooq_csv(body: &str) -> FetchResult<Vec<Candle>> {
+    let mut lines = body.lines().map(str::trim).filter(|l| !l.is_empty());
+
+    let header = lines
+        .next()
+        .ok_or_else(|| FetchError::Parse("CSV vuoto".to_string()))?;
+    // Stooq risponde 200 con "No data" per i simboli sconosciuti
+    if header.eq_ignore_ascii_case("no data") {
+        return Err(FetchError::Provider {
+            code: "No data".to_string(),
+            description: "simbolo sconosciuto a Stooq".to_string(),
+        });
+    }
+
+    let columns: Vec<String> = header.split(',').map(|c| c.trim().to_lowercase()).collect();
+    let column = |name: &str| {
+        columns
+            .iter()
+            .position(|c| c == name)
+            .ok_or_else(|| FetchError::Parse(format!("colonna {} mancante", name)))
+    };
+    let (date_i, open_i, high_i, low_i, close_i) = (
+        column("date")?,
+        column("open")?,
+        column("high")?,
+        column("low")?,
+        column("close")?,
+    );
+    let volume_i = column("volume").ok();
+
+    let mut candles = Vec::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let number = |i: usize| fields.get(i).and_then(|v| v.parse::<f32>().ok());
+
+        // Righe incomplete (es. valori "N/D") vengono saltate
+        let Some(date) = fields
+            .get(date_i)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        else {
+            continue;
+        };
+        let (Some(open), Some(high), Some(low), Some(close)) = (
+            number(open_i),
+            number(high_i),
+            number(low_i),
+            number(close_i),
+        ) else {
+            continue;
+        };
+
+        candles.push(Candle {
+            time: date.and_time(NaiveTime::MIN).and_utc(),
+            open,
+            high,
+            low,
+            close,
+            volume: volume_i
+                .and_then(|i| fields.get(i))
+                .and_then(|v| v.parse::<f64>().ok())
+                .map(|v| v as u64)
+                .unwrap_or(0),
+        });
+    }
+
+    if candles.is_empty() {
+        return Err(FetchError::Parse("CSV senza righe valide".to_string()));
+    }
+    Ok(candles)
+}
+
+// Prova i provider in ordine: il primo che risponde vince.
+// In caso di fallimento totale riporta l'errore del provider principale.
+struct FallbackProvider {
+    name: String,
+    providers: Vec<Arc<dyn MarketDataProvider>>,
+}
+
+impl FallbackProvider {
+    fn new(providers: Vec<Arc<dyn MarketDataProvider>>) -> Self {
+        let name = providers
+            .iter()
+            .map(|p| p.name())
+            .collect::<Vec<_>>()
+            .join("+");
+        Self { name, providers }
+    }
+
+    fn first_ok<T>(&self, f: impl Fn(&dyn MarketDataProvider) -> FetchResult<T>) -> FetchResult<T> {
+        let mut first_err = None;
+        for provider in &self.providers {
+            match f(provider.as_ref()) {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        Err(first_err.unwrap_or_else(|| FetchError::Network("nessun provider".to_string())))
+    }
+}
+
+impl MarketDataProvider for FallbackProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn fetch_quote(&self, symbol: &str) -> FetchResult<Quote> {
+        self.first_ok(|p| p.fetch_quote(symbol))
+    }
+
+    fn supports_batch(&self) -> bool {
+        self.providers.first().is_some_and(|p| p.supports_batch())
+    }
+
+    // Batch sul principale, poi fallback simbolo per simbolo solo per gli errori
+    fn fetch_quotes(
+        &self,
+        symbols: &[String],
+        concurrency: usize,
+    ) -> Vec<(String, FetchResult<Quote>)> {
+        let Some((primary, rest)) = self.providers.split_first() else {
+            return Vec::new();
+        };
+
+        let mut out = Vec::with_capacity(symbols.len());
+        let mut failed = Vec::new();
+        for (symbol, result) in primary.fetch_quotes(symbols, concurrency) {
+            match result {
+                Ok(quote) => out.push((symbol, Ok(quote))),
+                Err(err) => failed.push((symbol, err)),
+            }
+        }
+
+        let retried = Mutex::new(Vec::with_capacity(failed.len()));
+        for_each_bounded(&failed, concurrency, |(symbol, err)| {
+            let result = rest
+                .iter()
+                .find_map(|p| p.fetch_quote(symbol).ok())
+                .ok_or_else(|| err.clone());
+            if let Ok(mut retried) = retried.lock() {
+                retried.push((symbol.clone(), result));
+            }
+        });
+        out.extend(retried.into_inner().unwrap_or_default());
+        out
+    }
+
+    fn fetch_history(&self, symbol: &str, range: ChartRange) -> FetchResult<History> {
+        self.first_ok(|p| p.fetch_history(symbol, range))
+    }
+
+    fn search_symbols(&self, query: &str) -> FetchResult<Vec<SymbolMatch>> {
+        self.first_ok(|p| p.search_symbols(query))
+    }
+}
+
+// Costruisce la catena indicata in --provider (es. "yahoo,stooq")
+fn build_provider(
+    config: &Config,
+    limiter: &Arc<RateLimiter>,
+    retry: &RetryPolicy,
+    tape: &Tape,
+) -> Arc<dyn MarketDataProvider> {
+    let http = || HttpClient::new(limiter.clone(), retry.clone(), tape.clone());
+
+    let mut providers: Vec<Arc<dyn MarketDataProvider>> = Vec::new();
+    for name in &config.providers {
+        match name.as_str() {
+            "yahoo" => providers.push(Arc::new(YahooProvider::new(&config.yahoo_base_url, http()))),
+            "stooq" => providers.push(Arc::new(StooqProvider::new(&config.stooq_base_url, http()))),
+            "file" => providers.push(Arc::new(FileProvider::new(&config.data_dir))),
+            "sim" => providers.push(Arc::new(SimulatorProvider::new(config.sim.clone()))),
+            other => eprintln!("Provider sconosciuto ignorato: {}", other),
+        }
+    }
+
+    match providers.len() {
+        0 => Arc::new(YahooProvider::new(&config.yahoo_base_url, http())),
+        1 => providers.remove(0),
+        _ => Arc::new(FallbackProvider::new(providers)),
+    }
+}
+
+// ────────────────────────────────────────────────
+// Provider da file locali (offline / air-gapped)
+// ────────────────────────────────────────────────
+
+// Una cartella con un file per simbolo: <SIMBOLO>.json (risposta chart di
+// Yahoo salvata) oppure <SIMBOLO>.csv (formato Stooq).
+struct FileProvider {
+    dir: PathBuf,
+}
+
+enum SymbolFile {
+    Chart(Box<ChartResult>),
+    Csv(Vec<Candle>),
+}
+
+impl FileProvider {
+    fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    fn load(&self, symbol: &str) -> FetchResult<SymbolFile> {
+        for name in [symbol.to_string(), symbol.to_lowercase()] {
+            let json = self.dir.join(format!("{}.json", name));
+            if json.is_file() {
+                let body = fs::read_to_string(&json)?;
+                return parse_chart_response(&body).map(|r| SymbolFile::Chart(Box::new(r)));
+            }
+
+            let csv = self.dir.join(format!("{}.csv", name));
+            if csv.is_file() {
+                let body = fs::read_to_string(&csv)?;
+                return parse_stooq_csv(&body).map(SymbolFile::Csv);
+            }
+        }
+
+        Err(FetchError::Provider {
+            code: "Not Found".to_string(),
+            description: format!("nessun file per {} in {}", symbol, self.dir.display()),
+        })
+    }
+}
+
+impl MarketDataProvider for FileProvider {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn fetch_quote(&self, symbol: &str) -> FetchResult<Quote> {
+        match self.load(symbol)? {
+            SymbolFile::Chart(result) => Ok(Quote::from_chart(&result)),
+            SymbolFile::Csv(candles) => quote_from_candles(&candles),
+        }
+    }
+
+    fn fetch_history(&self, symbol: &str, range: ChartRange) -> FetchResult<History> {
+        let mut candles = match self.load(symbol)? {
+            SymbolFile::Chart(result) => candles_from_chart(&result)?,
+            SymbolFile::Csv(candles) => candles,
+        };
+        trim_to_range(&mut candles, range);
+        Ok(candles)
+    }
+
+    // Cerca tra i nomi dei file; nome e borsa vengono dai meta dei JSON
+    fn search_symbols(&self, query: &str) -> FetchResult<Vec<SymbolMatch>> {
+        let query = query.to_uppercase();
+        let mut symbols: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                let ext = path.extension()?.to_str()?;
+                if ext != "json" && ext != "csv" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_uppercase())
+            })
+            .filter(|symbol| symbol.contains(&query))
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        symbols.truncate(SEARCH_MAX_RESULTS);
+
+        Ok(symbols
+            .into_iter()
+            .map(|symbol| match self.load(&symbol) {
+                Ok(SymbolFile::Chart(result)) => SymbolMatch {
+                    name: result.meta.long_name.or(result.meta.short_name),
+                    exchange: result.meta.full_exchange_name.or(result.meta.exchange_name),
+                    kind: result.meta.instrument_type,
+                    symbol,
+                },
+                _ => SymbolMatch {
+                    symbol,
+                    name: None,
+                    exchange: None,
+                    kind: None,
+                },
+            })
+            .collect())
+    }
+}
+
+// ────────────────────────────────────────────────
+// Simulatore di mercato (moto browniano geometrico)
+// ────────────────────────────────────────────────
+
+const SECONDS_PER_YEAR: f64 = 365.0 * 24.0 * 3600.0;
+// Durata simulata di un passo GBM
+const SIM_STEP_SECS: f64 = 1.0;
+
+#[derive(Debug, Clone)]
+struct SimParams {
+    // Drift e volatilità annualizzati (es. 0.05 e 0.30)
+    drift: f64,
+    volatility: f64,
+    seed: u64,
+    // Passi di un secondo simulato per ogni quote: il percorso dipende solo
+    // dal numero di richieste, non da quando arrivano
+    steps_per_quote: u32,
+}
+
+struct SimState {
+    rng: SplitMix64,
+    price: f64,
+    // Prezzo all'avvio, fa da "chiusura precedente" per la variazione %
+    reference: f64,
+}
+
+// Prezzi finti ma realistici per qualsiasi simbolo, deterministici a parità di seed
+struct SimulatorProvider {
+    params: SimParams,
+    states: Mutex<HashMap<String, SimState>>,
+}
+
+impl SimulatorProvider {
+    fn new(params: SimParams) -> Self {
+        Self {
+            params,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn symbol_seed(&self, symbol: &str) -> u64 {
+        // FNV-1a del simbolo combinato con il seed globale
+        let hash = symbol.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+        hash ^ self.params.seed
+    }
+
+    fn initial_price(&self, symbol: &str) -> f64 {
+        let mut rng = SplitMix64::new(self.symbol_seed(symbol));
+        // Tra 5 e 500, distribuito in scala logaritmica
+        5.0 * 100f64.powf(rng.next_f64())
+    }
+
+    // Un passo GBM di durata `dt` secondi
+    fn step(&self, price: f64, dt: f64, rng: &mut SplitMix64) -> f64 {
+        let t = dt / SECONDS_PER_YEAR;
+        let sigma = self.params.volatility;
+        price
+            * ((self.params.drift - 0.5 * sigma * sigma) * t + sigma * t.sqrt() * rng.next_normal())
+                .exp()
+    }
+}
+
+impl MarketDataProvider for SimulatorProvider {
+    fn name(&self) -> &str {
+        "sim"
+    }
+
+    fn fetch_quote(&self, symbol: &str) -> FetchResult<Quote> {
+        let mut states = self.states.lock().map_err(|_| FetchError::Provider {
+            code: "Poisoned".to_string(),
+            description: "stato del simulatore non disponibile".to_string(),
+        })?;
+
+        let state = states.entry(symbol.to_string()).or_insert_with(|| {
+            let price = self.initial_price(symbol);
+            SimState {
+                rng: SplitMix64::new(self.symbol_seed(symbol).rotate_left(17)),
+                price,
+                reference: price,
+            }
+        });
+
+        for _ in 0..self.params.steps_per_quote {
+            state.price = self.step(state.price, SIM_STEP_SECS, &mut state.rng);
+        }
+
+        Ok(Quote {
+            price: state.price as f32,
+            change_percent: ((state.price - state.reference) / state.reference * 100.0) as f32,
+            market_time: None,
+            session: None,
+            extended: None,
+            currency: None,
+            price_hint: None,
+        })
+    }
+
+    // Percorso generato in avanti e poi scalato perché l'ultima chiusura
+    // coincida con il prezzo iniziale, da cui partono le quote: lo storico
+    // non dipende da quante quote sono già state chieste
+    fn fetch_history(&self, symbol: &str, range: ChartRange) -> FetchResult<History> {
+        let anchor = self.initial_price(symbol);
+
+        let candle_secs = range.candle_duration().num_seconds();
+        let span_secs = range
+            .span()
+            .unwrap_or(chrono::Duration::days(20 * 365))
+            .num_seconds();
+        let count = (span_secs / candle_secs).max(2) as usize;
+
+        let substeps = 4;
+        let dt = candle_secs as f64 / substeps as f64;
+        let mut rng = SplitMix64::new(self.symbol_seed(symbol) ^ range as u64);
+        let mut price = 1.0;
+        let mut raw = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let open = price;
+            let (mut high, mut low) = (open, open);
+            for _ in 0..substeps {
+                price = self.step(price, dt, &mut rng);
+                high = high.max(price);
+                low = low.min(price);
+            }
+            let volume = (1_000.0 + rng.next_f64() * 99_000.0) as u64;
+            raw.push((open, high, low, price, volume));
+        }
+
+        let scale = anchor / price;
+        let start = Utc::now() - chrono::Duration::seconds(candle_secs * count as i64);
+
+        Ok(raw
+            .into_iter()
+            .enumerate()
+            .map(|(i, (open, high, low, close, volume))| Candle {
+                time: start + chrono::Duration::seconds(candle_secs * (i as i64 + 1)),
+                open: (open * scale) as f32,
+                high: (high * scale) as f32,
+                low: (low * scale) as f32,
+                close: (close * scale) as f32,
+                volume,
+            })
+            .collect())
+    }
+}
+
+// ────────────────────────────────────────────────
+// HTTP condiviso: pool di connessioni, retry con backoff e rate limit
+// ────────────────────────────────────────────────
+
+// Generatore pseudo-casuale minimale (SplitMix64): jitter e simulatore
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self(nanos)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniforme in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Normale standard (Box–Muller)
+    fn next_normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Backoff esponenziale con "full jitter": attesa casuale in [0, base * 2^tentativo]
+    fn delay(&self, attempt: u32, rng: &mut SplitMix64) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(1u32 << attempt.min(16))
+            .min(self.max_delay);
+        cap.mul_f64(rng.next_f64())
+    }
+}
+
+// Token bucket condiviso da tutti i worker: `rate` richieste/s con raffiche
+// fino a `burst`. Un 429 sospende tutte le richieste fino al Retry-After.
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                last_refill: Instant::now(),
+                blocked_until: None,
+            }),
+        }
+    }
+
+    // Blocca il thread finché non è disponibile un token
+    fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut st = self.state.lock().unwrap();
+                let now = Instant::now();
+
+                match st.blocked_until {
+                    Some(until) if until > now => until - now,
+                    _ => {
+                        st.blocked_until = None;
+                        let elapsed = now.duration_since(st.last_refill).as_secs_f64();
+                        st.tokens = (st.tokens + elapsed * self.rate).min(self.burst);
+                        st.last_refill = now;
+
+                        if st.tokens >= 1.0 {
+                            st.tokens -= 1.0;
+                            return;
+                        }
+                        Duration::from_secs_f64((1.0 - st.tokens) / self.rate)
+                    }
+                }
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    fn pause_for(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut st = self.state.lock().unwrap();
+        if st.blocked_until.is_none_or(|current| current < until) {
+            st.blocked_until = Some(until);
+        }
+    }
+}
+
+struct HttpClient {
+    client: reqwest::blocking::Client,
+    limiter: Arc<RateLimiter>,
+    retry: RetryPolicy,
+    tape: Tape,
+}
+
+impl HttpClient {
+    fn new(limiter: Arc<RateLimiter>, retry: RetryPolicy, tape: Tape) -> Self {
+        // Client unico: riusa il pool di connessioni tra le richieste
+        let client = reqwest::blocking::Client::builder()
+            .user_agent("Mozilla/5.0")
+            .timeout(Duration::from_secs(10))
+            .pool_max_idle_per_host(8)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            client,
+            limiter,
+            retry,
+            tape,
+        }
+    }
+
+    fn get_text(&self, url: &str) -> FetchResult<String> {
+        // In replay la rete non si tocca: niente rate limit né retry
+        if let Tape::Replay(replay) = &self.tape {
+            return replay.respond(url);
+        }
+
+        let mut rng = SplitMix64::from_time();
+        let mut attempt = 0;
+
+        loop {
+            self.limiter.acquire();
+
+            let err = match self.get_once(url) {
+                Ok(body) => return Ok(body),
+                Err(err) => err,
+            };
+
+            if !err.is_retryable() || attempt >= self.retry.max_retries {
+                return Err(err);
+            }
+
+            let backoff = self.retry.delay(attempt, &mut rng);
+            match err.retry_after() {
+                // Rate limit: ferma tutti i worker, non solo questo
+                Some(wait) => self.limiter.pause_for(wait.max(backoff)),
+                None => thread::sleep(backoff),
+            }
+            attempt += 1;
+        }
+    }
+
+    fn get_once(&self, url: &str) -> FetchResult<String> {
+        let response = self.client.get(url).send()?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = response.text()?;
+
+        if let Tape::Record(recorder) = &self.tape {
+            recorder.record(url, status.as_u16(), &body);
+        }
+
+        if !status.is_success() {
+            return Err(FetchError::Http {
+                status: status.as_u16(),
+                retry_after,
+            });
+        }
+
+        Ok(body)
+    }
+}
+
+// ────────────────────────────────────────────────
+// Registrazione e replay delle risposte HTTP dei provider
+// ────────────────────────────────────────────────
+
+// Una riga JSON per risposta. Solo i provider HTTP (yahoo, stooq) passano
+// di qui: "file" e "sim" sono già riproducibili di loro.
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionEntry {
+    time: DateTime<Utc>,
+    url: String,
+    status: u16,
+    body: String,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Tape {
+    #[default]
+    Off,
+    Record(Arc<SessionRecorder>),
+    Replay(Arc<SessionReplay>),
+}
+
+impl Tape {
+    // In replay l'ora è quella della sessione registrata: le date nelle URL
+    // (es. d1 di Stooq) coincidono con quelle registrate
+    fn now(&self) -> DateTime<Utc> {
+        match self {
+            Tape::Replay(replay) => replay.clock(),
+            Tape::Off | Tape::Record(_) => Utc::now(),
+        }
+    }
+
+    // Attesa reale corrispondente a un intervallo dell'orologio di `now()`
+    fn real_duration(&self, span: chrono::Duration) -> Duration {
+        let span = span.to_std().unwrap_or_default();
+        match self {
+            Tape::Replay(replay) => span.div_f64(replay.speed),
+            Tape::Off | Tape::Record(_) => span,
+        }
+    }
+}
+
+// Percorso + query senza schema e host: un replay con --yahoo-url diverso
+// dalla registrazione (es. un mock locale) trova comunque le risposte
+fn session_key(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.find('/').map_or("/", |i| &rest[i..])
+}
+
+const SPARK_PATH: &str = "/v7/finance/spark?";
+
+// Quali simboli finiscono in un batch spark dipende da tempi e priorità:
+// in replay ogni batch è diviso in risposte per simbolo. Ritorna i simboli
+// e la chiave di ciascuno (stessa query con un solo simbolo).
+fn split_spark_key(key: &str) -> Option<Vec<(String, String)>> {
+    let query = key.strip_prefix(SPARK_PATH)?;
+    let mut symbols = None;
+    let mut rest = Vec::new();
+    for param in query.split('&') {
+        match param.strip_prefix("symbols=") {
+            Some(list) => symbols = Some(list),
+            None => rest.push(param),
+        }
+    }
+    let rest = rest.join("&");
+    Some(
+        symbols?
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|symbol| {
+                let key = format!("{}symbols={}&{}", SPARK_PATH, symbol, rest);
+                (symbol.to_string(), key)
+            })
+            .collect(),
+    )
+}
+
+#[derive(Debug)]
+struct SessionRecorder {
+    file: Mutex<fs::File>,
+}
+
+impl SessionRecorder {
+    fn create(path: &str) -> std::io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, url: &str, status: u16, body: &str) {
+        let entry = SessionEntry {
+            time: Utc::now(),
+            url: url.to_string(),
+            status,
+            body: body.to_string(),
+        };
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        if let Ok(mut file) = self.file.lock()
+            && let Err(err) = writeln!(file, "{}", line)
+        {
+            eprintln!("Registrazione sessione: {}", err);
+        }
+    }
+}
+
+// Riproduce una sessione con un orologio virtuale che parte dalla prima
+// risposta registrata e avanza `speed` volte più veloce di quello reale.
+// Ogni richiesta riceve l'ultima risposta registrata per lo stesso URL
+// entro l'istante virtuale (la prima, se la sessione non è ancora arrivata lì).
+#[derive(Debug)]
+struct SessionReplay {
+    responses: HashMap<String, Vec<SessionEntry>>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    started_at: Instant,
+    speed: f64,
+}
+
+impl SessionReplay {
+    fn load(path: &str, speed: f64) -> FetchResult<Self> {
+        let body = fs::read_to_string(path)?;
+        let mut responses: HashMap<String, Vec<SessionEntry>> = HashMap::new();
+        for line in body.lines().filter(|l| !l.trim().is_empty()) {
+            let entry: SessionEntry = serde_json::from_str(line)?;
+            let key = session_key(&entry.url).to_string();
+            match split_spark_key(&key) {
+                Some(symbols) => {
+                    for (symbol, key) in symbols {
+                        responses
+                            .entry(key)
+                            .or_default()
+                            .push(spark_entry_for(&entry, &symbol));
+                    }
+                }
+                None => responses.entry(key).or_default().push(entry),
+            }
+        }
+
+        let times = || responses.values().flatten().map(|e| e.time);
+        let (Some(start), Some(end)) = (times().min(), times().max()) else {
+            return Err(FetchError::Parse(format!("sessione vuota: {}", path)));
+        };
+        for entries in responses.values_mut() {
+            entries.sort_by_key(|e| e.time);
+        }
+
+        Ok(Self {
+            responses,
+            start,
+            end,
+            started_at: Instant::now(),
+            speed,
+        })
+    }
+
+    // Istante della sessione registrata che si sta riproducendo
+    fn clock(&self) -> DateTime<Utc> {
+        let elapsed = self.started_at.elapsed().mul_f64(self.speed);
+        let now = self.start + chrono::Duration::from_std(elapsed).unwrap_or_default();
+        now.min(self.end)
+    }
+
+    fn finished(&self) -> bool {
+        self.clock() >= self.end
+    }
+
+    fn respond(&self, url: &str) -> FetchResult<String> {
+        let key = session_key(url);
+        match split_spark_key(key) {
+            Some(symbols) => self.respond_spark(&symbols),
+            None => self.lookup(key),
+        }
+    }
+
+    // Ricompone il batch richiesto dalle risposte dei singoli simboli;
+    // quelli senza risposta mancano e risultano "assenti nel batch"
+    fn respond_spark(&self, symbols: &[(String, String)]) -> FetchResult<String> {
+        let mut results = Vec::new();
+        let mut first_err = None;
+        for (_, key) in symbols {
+            let parsed = self.lookup(key).and_then(|body| {
+                let value: serde_json::Value = serde_json::from_str(&body)?;
+                Ok(value["spark"]["result"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default())
+            });
+            match parsed {
+                Ok(result) => results.extend(result),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        match first_err {
+            Some(err) if results.is_empty() => Err(err),
+            _ => Ok(
+                serde_json::json!({ "spark": { "result": results, "error": null } }).to_string(),
+            ),
+        }
+    }
+
+    fn lookup(&self, key: &str) -> FetchResult<String> {
+        let Some(entries) = self.responses.get(key) else {
+            return Err(FetchError::Io(format!(
+                "nessuna risposta registrata per {}",
+                key
+            )));
+        };
+
+        let now = self.clock();
+        let idx = entries.partition_point(|e| e.time <= now).saturating_sub(1);
+        let entry = &entries[idx];
+        if (200..300).contains(&entry.status) {
+            Ok(entry.body.clone())
+        } else {
+            Err(FetchError::Http {
+                status: entry.status,
+                retry_after: None,
+            })
+        }
+    }
+}
+
+// Parte di una risposta spark registrata relativa a un solo simbolo.
+// Gli errori HTTP valgono per tutti i simboli del batch.
+fn spark_entry_for(entry: &SessionEntry, symbol: &str) -> SessionEntry {
+    let mut single = SessionEntry {
+        time: entry.time,
+        url: entry.url.clone(),
+        status: entry.status,
+        body: entry.body.clone(),
+    };
+    if !(200..300).contains(&entry.status) {
+        return single;
+    }
+    let results = serde_json::from_str::<serde_json::Value>(&entry.body)
+        .ok()
+        .and_then(|value| value["spark"]["result"].as_array().cloned())
+        .unwrap_or_default();
+    let result: Vec<serde_json::Value> = results
+        .into_iter()
+        .filter(|r| r["symbol"].as_str() == Some(symbol))
+        .collect();
+    single.body = serde_json::json!({ "spark": { "result": result, "error": null } }).to_string();
+    single
+}
+
+// ────────────────────────────────────────────────
+// Modelli serde delle risposte /v8/finance/chart e /v7/finance/spark
+// (schema completo: non tutti i campi sono già usati dalla UI)
+// ────────────────────────────────────────────────
+
+#[derive(Debug, Deserialize)]
+struct ChartResponse {
+    chart: ChartEnvelope,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChartEnvelope {
+    result: Option<Vec<ChartResult>>,
+    error: Option<ChartError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SparkResponse {
+    spark: SparkEnvelope,
+}
+
+#[derive(Debug, Deserialize)]
+struct SparkEnvelope {
+    result: Option<Vec<SparkResult>>,
+    error: Option<ChartError>,
+}
+
+// Ogni simbolo di /v7/finance/spark contiene un normale result di chart
+#[derive(Debug, Deserialize)]
+struct SparkResult {
+    symbol: String,
+    #[serde(default)]
+    response: Vec<ChartResult>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ChartError {
+    code: String,
+    description: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+struct ChartResult {
+    meta: ChartMeta,
+    #[serde(default)]
+    timestamp: Vec<i64>,
+    indicators: ChartIndicators,
+    #[serde(default)]
+    events: Option<ChartEvents>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+struct ChartMeta {
+    symbol: String,
+    // Obbligatorio: senza prezzo la risposta non è utilizzabile
+    regular_market_price: f64,
+    currency: Option<String>,
+    exchange_name: Option<String>,
+    full_exchange_name: Option<String>,
+    instrument_type: Option<String>,
+    long_name: Option<String>,
+    short_name: Option<String>,
+    first_trade_date: Option<i64>,
+    regular_market_time: Option<i64>,
+    gmtoffset: Option<i64>,
+    timezone: Option<String>,
+    exchange_timezone_name: Option<String>,
+    chart_previous_close: Option<f64>,
+    previous_close: Option<f64>,
+    regular_market_day_high: Option<f64>,
+    regular_market_day_low: Option<f64>,
+    regular_market_volume: Option<u64>,
+    fifty_two_week_high: Option<f64>,
+    fifty_two_week_low: Option<f64>,
+    price_hint: Option<u32>,
+    current_trading_period: Option<TradingPeriods>,
+    data_granularity: Option<String>,
+    range: Option<String>,
+    #[serde(default)]
+    valid_ranges: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TradingPeriods {
+    pre: TradingPeriod,
+    regular: TradingPeriod,
+    post: TradingPeriod,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TradingPeriod {
+    timezone: String,
+    start: i64,
+    end: i64,
+    gmtoffset: i64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ChartIndicators {
+    #[serde(default)]
+    quote: Vec<QuoteIndicator>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct QuoteIndicator {
+    #[serde(default)]
+    open: Vec<Option<f64>>,
+    #[serde(default)]
+    high: Vec<Option<f64>>,
+    #[serde(default)]
+    low: Vec<Option<f64>>,
+    #[serde(default)]
+    close: Vec<Option<f64>>,
+    #[serde(default)]
+    volume: Vec<Option<u64>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[allow(dead_code)]
+struct ChartEvents {
+    #[serde(default)]
+    dividends: HashMap<String, DividendEvent>,
+    #[serde(default)]
+    splits: HashMap<String, SplitEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+struct DividendEvent {
+    amount: f64,
+    date: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+struct SplitEvent {
+    date: i64,
+    numerator: f64,
+    denominator: f64,
+    split_ratio: String,
+}
+
+// Risposta di /v1/finance/search (le notizie non vengono richieste)
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    quotes: Vec<SearchQuote>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchQuote {
+    symbol: Option<String>,
+    shortname: Option<String>,
+    longname: Option<String>,
+    exchange: Option<String>,
+    exch_disp: Option<String>,
+    quote_type: Option<String>,
+    type_disp: Option<String>,
+}
+
+// ────────────────────────────────────────────────
+// Scrollbar (leggermente ottimizzata)
+// ────────────────────────────────────────────────
+
+#[allow(clippy::too_many_arguments)]
+fn draw_scrollbar(
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    scroll_offset: f32,
+    total_content_height: f32,
+    visible_height: f32,
+    scrollbar_state: &mut ScrollbarState,
+) -> f32 {
+    let scrollbar_width = 12.0;
+    let scrollbar_x = x + width - scrollbar_width - 5.0;
+    let scrollbar_y = y;
+
+    draw_rectangle(
+        scrollbar_x,
+        scrollbar_y,
+        scrollbar_width,
+        height,
+        Color::from_rgba(20, 20, 25, 255),
+    );
+    draw_rectangle_lines(
+        scrollbar_x,
+        scrollbar_y,
+        scrollbar_width,
+        height,
+        1.0,
+        Color::from_rgba(40, 40, 50, 255),
+    );
+
+    let max_scroll = (total_content_height - visible_height).max(0.0);
+    if max_scroll <= 0.0 {
+        return scroll_offset;
+    }
+
+    let thumb_height = ((visible_height / total_content_height) * height)
+        .max(30.0)
+        .min(height - 10.0);
+    let scroll_range = height - thumb_height;
+    let thumb_y = scrollbar_y + (scroll_offset / max_scroll) * scroll_range;
+
+    let (mx, my) = mouse_position();
+    let is_on_scrollbar = mx >= scrollbar_x
+        && mx <= scrollbar_x + scrollbar_width
+        && my >= scrollbar_y
+        && my <= scrollbar_y + height;
+    let is_on_thumb = is_on_scrollbar && my >= thumb_y && my <= thumb_y + thumb_height;
+
+    let thumb_color = if scrollbar_state.dragging {
+        Color::from_rgba(120, 120, 140, 255)
+    } else if is_on_thumb {
+        Color::from_rgba(100, 100, 120, 255)
+    } else if is_on_scrollbar {
+        Color::from_rgba(80, 80, 100, 255)
+    } else {
+        Color::from_rgba(60, 60, 80, 255)
+    };
+
+    draw_rectangle(
+        scrollbar_x + 2.0,
+        thumb_y + 2.0,
+        scrollbar_width - 4.0,
+        thumb_height - 4.0,
+        thumb_color,
+    );
+
+    // tre linee sul thumb
+    let indicator_y = thumb_y + thumb_height / 2.0;
+    for i in 0..3 {
+        let ly = indicator_y - 2.0 + (i as f32 * 2.0);
+        draw_line(
+            scrollbar_x + 4.0,
+            ly,
+            scrollbar_x + scrollbar_width - 4.0,
+            ly,
+            1.0,
+            Color::from_rgba(40, 40, 50, 200),
+        );
+    }
+
+    if is_mouse_button_pressed(MouseButton::Left) && is_on_thumb {
+        scrollbar_state.dragging = true;
+        scrollbar_state.drag_start_y = my;
+        scrollbar_state.drag_start_scroll = scroll_offset;
+    }
+
+    if is_mouse_button_released(MouseButton::Left) {
+        scrollbar_state.dragging = false;
+    }
+
+    if scrollbar_state.dragging {
+        let delta_y = my - scrollbar_state.drag_start_y;
+        let scroll_delta = (delta_y / scroll_range) * max_scroll;
+        let new_scroll = (scrollbar_state.drag_start_scroll + scroll_delta).clamp(0.0, max_scroll);
+        return new_scroll;
+    }
+
+    // click sulla track
+    if is_mouse_button_pressed(MouseButton::Left) && is_on_scrollbar && !is_on_thumb {
+        let click_ratio = (my - scrollbar_y) / height;
+        let new_scroll = (click_ratio * max_scroll).clamp(0.0, max_scroll);
+        return new_scroll;
+    }
+
+    scroll_offset
+}
+
+// ────────────────────────────────────────────────
+// Valute e conversione nella valuta base
+// ────────────────────────────────────────────────
+
+// Stooq, simulatore e file locali non riportano la valuta: si assume il
+// dollaro, come faceva l'interfaccia prima di leggerla dal provider
+const DEFAULT_CURRENCY: &str = "USD";
+
+// Alcune borse quotano in centesimi (GBp a Londra, ZAc a Johannesburg,
+// ILA a Tel Aviv): valuta principale e fattore per passare a quella
+fn currency_unit(code: &str) -> (&str, f32) {
+    match code {
+        "GBp" | "GBX" => ("GBP", 0.01),
+        "ZAc" | "ZAC" => ("ZAR", 0.01),
+        "ILA" => ("ILS", 0.01),
+        _ => (code, 1.0),
+    }
+}
+
+// Prefisso e suffisso con cui si scrive un importo; le valute senza
+// simbolo noto, o con un simbolo assente dal font predefinito (₹, ₩),
+// usano il codice ISO dopo il valore
+fn currency_affixes(code: &str) -> (String, String) {
+    let prefix = match code {
+        "USD" => "$",
+        "EUR" => "€",
+        "GBP" => "£",
+        "JPY" | "CNY" => "¥",
+        "CAD" => "C$",
+        "AUD" => "A$",
+        "HKD" => "HK$",
+        "BRL" => "R$",
+        "GBp" | "GBX" => return (String::new(), "p".to_string()),
+        _ => return (String::new(), format!(" {}", code)),
+    };
+    (prefix.to_string(), String::new())
+}
+
+// Cambio quotato come un simbolo qualsiasi: prezzo = unità di `base` per
+// un'unità di `code` (USDEUR=X → euro per dollaro)
+fn fx_symbol(code: &str, base: &str) -> String {
+    format!("{}{}=X", code, base)
+}
+
+const MIN_PRICE_DECIMALS: usize = 2;
+const MAX_PRICE_DECIMALS: usize = 8;
+
+// Sotto l'unità si tengono almeno 4 cifre significative: DOGE a 0.1234,
+// SHIB a 0.00001234. Da 1 in su bastano i centesimi.
+fn price_decimals(price: f32) -> usize {
+    let price = price.abs();
+    if !price.is_finite() || price == 0.0 || price >= 1.0 {
+        return MIN_PRICE_DECIMALS;
+    }
+    let digits = 3 - price.log10().floor() as i32;
+    (digits.max(0) as usize).clamp(MIN_PRICE_DECIMALS, MAX_PRICE_DECIMALS)
+}
+
+// Come scrivere i prezzi di un titolo: simbolo della valuta, fattore di
+// conversione e decimali. Le percentuali restano quelle nella valuta di
+// quotazione.
+#[derive(Debug, Clone)]
+struct PriceFormat {
+    prefix: String,
+    suffix: String,
+    factor: f32,
+    decimals: usize,
+}
+
+impl PriceFormat {
+    // Decimali dal priceHint del provider (i cambi ne hanno 4 anche sopra
+    // l'unità) o, se manca o il prezzo è convertito, dalla grandezza del prezzo
+    fn new(code: &str, factor: f32, stock: &StockData) -> Self {
+        let (prefix, suffix) = currency_affixes(code);
+        let magnitude = price_decimals(stock.current_price * factor);
+        let hint = stock
+            .price_hint
+            .filter(|_| factor == 1.0)
+            .map_or(0, |hint| hint as usize);
+        Self {
+            prefix,
+            suffix,
+            factor,
+            decimals: magnitude.max(hint).min(MAX_PRICE_DECIMALS),
+        }
+    }
+
+    // Con la valuta base il prezzo è convertito al cambio più recente; finché
+    // il cambio non è arrivato resta nella valuta di quotazione
+    fn for_stock(
+        stock: &StockData,
+        stocks: &HashMap<String, StockData>,
+        base: Option<&str>,
+    ) -> Self {
+        let code = stock.currency.as_deref().unwrap_or(DEFAULT_CURRENCY);
+        if let Some(base) = base {
+            let (major, unit) = currency_unit(code);
+            if major == base {
+                return Self::new(base, unit, stock);
+            }
+            if let Some(rate) = stocks
+                .get(&fx_symbol(major, base))
+                .map(|fx| fx.current_price)
+                .filter(|&rate| rate > 0.0)
+            {
+                return Self::new(base, unit * rate, stock);
+            }
+        }
+        Self::new(code, 1.0, stock)
+    }
+
+    fn format(&self, value: f32) -> String {
+        format!(
+            "{}{:.*}{}",
+            self.prefix,
+            self.decimals,
+            value * self.factor,
+            self.suffix
+        )
+    }
+}
+
+// ────────────────────────────────────────────────
+// Item lista con checkbox (invariato, ma chiamato meno volte)
+// ────────────────────────────────────────────────
+
+// Prezzi e tratti del grafico fuori dalla seduta regolare
+const EXTENDED_COLOR: Color = Color::new(0.75, 0.55, 1.0, 1.0);
+
+fn extended_label(phase: MarketStatus) -> &'static str {
+    match phase {
+        MarketStatus::Pre => "Pre-market",
+        _ => "After-hours",
+    }
+}
+
+// Click su un elemento della lista: selezione del grafico o rimozione
+#[derive(Debug)]
+enum ListAction {
+    Toggle(String),
+    Remove(String),
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_list_item(
+    stock: &StockData,
+    price_format: &PriceFormat,
+    calendar: &MarketCalendar,
+    now: DateTime<Utc>,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    is_selected: bool,
+    mouse_pos: (f32, f32),
+) -> Option<ListAction> {
+    let (mx, my) = mouse_pos;
+    let is_hovered = mx >= x && mx <= x + width && my >= y && my <= y + height;
+    let is_clicked = is_hovered && is_mouse_button_pressed(MouseButton::Left);
+
+    // Pulsante di rimozione in basso a destra
+    let rs = 14.0;
+    let rx = x + width - rs - 8.0;
+    let ry = y + height - rs - 8.0;
+    let remove_hovered = mx >= rx && mx <= rx + rs && my >= ry && my <= ry + rs;
+
+    let bg_color = if is_hovered {
+        Color::from_rgba(40, 40, 50, 255)
+    } else {
+        Color::from_rgba(30, 30, 40, 255)
+    };
+
+    draw_rectangle(x, y, width, height, bg_color);
+    draw_rectangle_lines(x, y, width, height, 1.0, Color::from_rgba(50, 50, 60, 255));
+
+    // Checkbox
+    let cs = 20.0;
+    let cx = x + 10.0;
+    let cy = y + (height - cs) / 2.0;
+
+    let checkbox_color = if is_selected {
+        Color::from_rgba(50, 100, 200, 255)
+    } else {
+        Color::from_rgba(40, 40, 50, 255)
+    };
+    draw_rectangle(cx, cy, cs, cs, checkbox_color);
+    draw_rectangle_lines(cx, cy, cs, cs, 2.0, Color::from_rgba(100, 100, 120, 255));
+
+    if is_selected {
+        draw_line(cx + 4.0, cy + 10.0, cx + 8.0, cy + 16.0, 2.0, WHITE);
+        draw_line(cx + 8.0, cy + 16.0, cx + 16.0, cy + 4.0, 2.0, WHITE);
+    }
+
+    draw_text(&stock.symbol, x + 45.0, y + 22.0, 20.0, WHITE);
+
+    if stock.current_price > 0.0 {
+        draw_text(
+            &price_format.format(stock.current_price),
+            x + 45.0,
+            y + 42.0,
+            20.0,
+            LIGHTGRAY,
+        );
+
+        let ch_color = if stock.change_percent >= 0.0 {
+            Color::from_rgba(0, 200, 100, 255)
+        } else {
+            Color::from_rgba(220, 50, 50, 255)
+        };
+        draw_text(
+            &format!("{:+.2}%", stock.change_percent),
+            x + 45.0,
+            y + 60.0,
+            16.0,
+            ch_color,
+        );
+
+        if stock.change_range_percent != 0.0 {
+            draw_text(
+                &format!(
+                    "{}: {:+.2}%",
+                    stock.range.label(),
+                    stock.change_range_percent
+                ),
+                x + width - 90.0,
+                y + 60.0,
+                16.0,
+                Color::from_rgba(100, 150, 255, 255),
+            );
+        }
+    } else if let Some(err) = &stock.last_error {
+        draw_text(
+            &err.short_label(),
+            x + 45.0,
+            y + 48.0,
+            16.0,
+            Color::from_rgba(220, 90, 90, 255),
+        );
+    } else {
+        draw_text("Caricamento...", x + 45.0, y + 48.0, 16.0, GRAY);
+    }
+
+    // Prezzo già presente ma ultimo aggiornamento fallito: segnalalo in alto a destra
+    if stock.current_price > 0.0
+        && let Some(err) = &stock.last_error
+    {
+        let label = err.short_label();
+        let tw = measure_text(&label, None, 14, 1.0).width;
+        draw_text(
+            &label,
+            x + width - tw - 10.0,
+            y + 22.0,
+            14.0,
+            Color::from_rgba(220, 90, 90, 255),
+        );
+    } else if stock.stale {
+        // Valori dello snapshot precedente, in attesa del primo aggiornamento
+        let label = "Cache";
+        let tw = measure_text(label, None, 14, 1.0).width;
+        draw_text(label, x + width - tw - 10.0, y + 22.0, 14.0, GRAY);
+    }
+
+    if let Some(ext) = &stock.extended {
+        draw_text(
+            &format!(
+                "{}: {} ({:+.2}%)",
+                extended_label(ext.phase),
+                price_format.format(ext.price),
+                ext.change_percent
+            ),
+            x + 45.0,
+            y + 78.0,
+            14.0,
+            EXTENDED_COLOR,
+        );
+    }
+
+    // Stato della borsa con il tempo mancante al prossimo cambio
+    if let Some(session) = &stock.session {
+        let (status, next_change) = session.status(now, calendar);
+        let label = format!("{} {}", status.label(), format_countdown(next_change - now));
+        let badge_color = match status {
+            MarketStatus::Open => Color::from_rgba(0, 140, 80, 255),
+            MarketStatus::Pre | MarketStatus::Post => Color::from_rgba(170, 110, 20, 255),
+            MarketStatus::Closed => Color::from_rgba(70, 70, 85, 255),
+        };
+        let tw = measure_text(&label, None, 13, 1.0).width;
+        let bx = x + width - tw - 16.0;
+        draw_rectangle(bx, y + 30.0, tw + 8.0, 16.0, badge_color);
+        draw_text(&label, bx + 4.0, y + 42.0, 13.0, WHITE);
+    }
+
+    if is_hovered {
+        let remove_color = if remove_hovered {
+            Color::from_rgba(220, 90, 90, 255)
+        } else {
+            Color::from_rgba(110, 110, 130, 255)
+        };
+        draw_line(
+            rx + 3.0,
+            ry + 3.0,
+            rx + rs - 3.0,
+            ry + rs - 3.0,
+            2.0,
+            remove_color,
+        );
+        draw_line(
+            rx + rs - 3.0,
+            ry + 3.0,
+            rx + 3.0,
+            ry + rs - 3.0,
+            2.0,
+            remove_color,
+        );
+    }
+
+    match (is_clicked, remove_hovered) {
+        (true, true) => Some(ListAction::Remove(stock.symbol.clone())),
+        (true, false) => Some(ListAction::Toggle(stock.symbol.clone())),
+        (false, _) => None,
+    }
+}
+
+// ────────────────────────────────────────────────
+// Casella di ricerca simboli (sopra la lista)
+// ────────────────────────────────────────────────
+
+const SEARCH_BOX_Y: f32 = 48.0;
+const SEARCH_BOX_H: f32 = 28.0;
+const SEARCH_ROW_H: f32 = 38.0;
+const SEARCH_MAX_QUERY: usize = 40;
+
+#[derive(Debug, Default)]
+struct SearchBox {
+    query: String,
+    focused: bool,
+    // Ultimo testo inviato al worker: solo i suoi risultati vengono mostrati
+    sent: String,
+    results: Vec<SymbolMatch>,
+    error: Option<FetchError>,
+    // Risultati arrivati per `sent` (false = ricerca in corso)
+    ready: bool,
+    highlighted: usize,
+}
+
+impl SearchBox {
+    // Testo da cercare, se è cambiato dall'ultimo invio
+    fn take_query(&mut self) -> Option<String> {
+        let query = self.query.trim();
+        if query == self.sent {
+            return None;
+        }
+        self.sent = query.to_string();
+        self.results.clear();
+        self.error = None;
+        self.ready = false;
+        self.highlighted = 0;
+        (!self.sent.is_empty()).then(|| self.sent.clone())
+    }
+
+    // Risposte arrivate dopo che il testo è cambiato vengono scartate
+    fn set_results(&mut self, query: String, result: FetchResult<Vec<SymbolMatch>>) {
+        if query != self.sent {
+            return;
+        }
+        match result {
+            Ok(results) => self.results = results,
+            Err(err) => self.error = Some(err),
+        }
+        self.ready = true;
+        self.highlighted = 0;
+    }
+
+    fn clear(&mut self) {
+        self.query.clear();
+        self.focused = false;
+    }
+
+    // Area del menu dei risultati: lì sotto la lista non riceve il mouse
+    fn dropdown_rect(&self, x: f32, y: f32, width: f32) -> Option<Rect> {
+        if !self.focused || self.sent.is_empty() {
+            return None;
+        }
+        let rows = self.results.len().max(1) as f32;
+        Some(Rect::new(x, y + SEARCH_BOX_H, width, rows * SEARCH_ROW_H))
+    }
+}
+
+// Accorcia il testo con "..." finché non sta in `max_width`
+// (il font predefinito di macroquad non ha il carattere "…")
+fn fit_text(text: &str, font_size: u16, max_width: f32) -> String {
+    if measure_text(text, None, font_size, 1.0).width <= max_width {
+        return text.to_string();
+    }
+    let mut fitted: String = text.to_string();
+    while !fitted.is_empty() {
+        fitted.pop();
+        let candidate = format!("{}...", fitted.trim_end());
+        if measure_text(&candidate, None, font_size, 1.0).width <= max_width {
+            return candidate;
+        }
+    }
+    String::new()
+}
+
+// Ritorna il simbolo da aggiungere alla lista: risultato cliccato, oppure
+// con Invio quello evidenziato (o il testo stesso se non ci sono risultati)
+fn draw_search_box(
+    search: &mut SearchBox,
+    watchlist: &Watchlist,
+    x: f32,
+    y: f32,
+    width: f32,
+) -> Option<String> {
+    let (mx, my) = mouse_position();
+    let clicked = is_mouse_button_pressed(MouseButton::Left);
+    let in_box = mx >= x && mx <= x + width && my >= y && my <= y + SEARCH_BOX_H;
+    let dropdown = search.dropdown_rect(x, y, width);
+    let in_dropdown = dropdown.is_some_and(|r| r.contains(vec2(mx, my)));
+    if clicked && !in_dropdown {
+        search.focused = in_box;
+    }
+
+    // I caratteri vanno consumati anche senza focus, o arriverebbero tutti insieme dopo
+    while let Some(c) = get_char_pressed() {
+        if search.focused && !c.is_control() && search.query.chars().count() < SEARCH_MAX_QUERY {
+            search.query.push(c);
+        }
+    }
+
+    let mut chosen = None;
+    if search.focused {
+        if is_key_pressed(KeyCode::Backspace) {
+            search.query.pop();
+        }
+        if is_key_pressed(KeyCode::Down) && search.highlighted + 1 < search.results.len() {
+            search.highlighted += 1;
+        }
+        if is_key_pressed(KeyCode::Up) {
+            search.highlighted = search.highlighted.saturating_sub(1);
+        }
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+            chosen = match search.results.get(search.highlighted) {
+                Some(result) => Some(result.symbol.clone()),
+                None => Some(search.query.trim().to_uppercase()).filter(|s| !s.is_empty()),
+            };
+        }
+        if is_key_pressed(KeyCode::Escape) {
+            search.clear();
+        }
+    }
+
+    // Campo di testo
+    let border = if search.focused {
+        Color::from_rgba(50, 100, 200, 255)
+    } else {
+        Color::from_rgba(60, 60, 75, 255)
+    };
+    draw_rectangle(x, y, width, SEARCH_BOX_H, Color::from_rgba(35, 35, 45, 255));
+    draw_rectangle_lines(x, y, width, SEARCH_BOX_H, 1.5, border);
+    if search.query.is_empty() && !search.focused {
+        draw_text("Cerca simbolo o nome...", x + 8.0, y + 19.0, 16.0, GRAY);
+    } else {
+        let text = fit_text(&search.query, 16, width - 20.0);
+        draw_text(&text, x + 8.0, y + 19.0, 16.0, WHITE);
+        if search.focused && get_time().fract() < 0.5 {
+            let cx = x + 10.0 + measure_text(&text, None, 16, 1.0).width;
+            draw_line(cx, y + 6.0, cx, y + SEARCH_BOX_H - 6.0, 1.0, WHITE);
+        }
+    }
+
+    // Menu dei risultati
+    if let Some(rect) = dropdown {
+        draw_rectangle(
+            rect.x,
+            rect.y,
+            rect.w,
+            rect.h,
+            Color::from_rgba(28, 28, 38, 250),
+        );
+        draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 1.0, border);
+
+        if search.results.is_empty() {
+            let status = if !search.ready {
+                "Ricerca...".to_string()
+            } else if let Some(err) = &search.error {
+                err.short_label()
+            } else {
+                "Nessun risultato (Invio per aggiungerlo comunque)".to_string()
+            };
+            draw_text(
+                &fit_text(&status, 14, rect.w - 16.0),
+                rect.x + 8.0,
+                rect.y + 24.0,
+                14.0,
+                GRAY,
+            );
+        }
+
+        for (i, result) in search.results.iter().enumerate() {
+            let ry = rect.y + i as f32 * SEARCH_ROW_H;
+            let hovered = in_dropdown && my >= ry && my < ry + SEARCH_ROW_H;
+            if hovered {
+                search.highlighted = i;
+                if clicked {
+                    chosen = Some(result.symbol.clone());
+                }
+            }
+            if i == search.highlighted {
+                draw_rectangle(
+                    rect.x,
+                    ry,
+                    rect.w,
+                    SEARCH_ROW_H,
+                    Color::from_rgba(45, 55, 80, 255),
+                );
+            }
+
+            draw_text(&result.symbol, rect.x + 8.0, ry + 16.0, 16.0, WHITE);
+            let sw = measure_text(&result.symbol, None, 16, 1.0).width;
+
+            let right = if watchlist.contains(&result.symbol) {
+                "in lista".to_string()
+            } else {
+                [result.exchange.as_deref(), result.kind.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(" · ")
+            };
+            let right = fit_text(&right, 13, rect.w - sw - 28.0);
+            let rw = measure_text(&right, None, 13, 1.0).width;
+            draw_text(&right, rect.x + rect.w - rw - 8.0, ry + 16.0, 13.0, GRAY);
+
+            if let Some(name) = &result.name {
+                draw_text(
+                    &fit_text(name, 13, rect.w - 16.0),
+                    rect.x + 8.0,
+                    ry + 32.0,
+                    13.0,
+                    LIGHTGRAY,
+                );
+            }
+        }
+    }
+
+    let chosen = chosen?;
+    search.clear();
+    Some(chosen)
+}
+
+// ────────────────────────────────────────────────
+// Pannello lista sinistra – SOLO ITEM VISIBILI
+// ────────────────────────────────────────────────
+
+#[allow(clippy::too_many_arguments)]
+fn draw_list_panel(
+    stocks: &HashMap<String, StockData>,
+    base_currency: Option<&str>,
+    calendar: &MarketCalendar,
+    // Orologio per lo stato della borsa: in replay è quello della sessione
+    now: DateTime<Utc>,
+    symbols: &[String],
+    selected_symbols: &HashSet<String>,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    scroll_offset: f32,
+    scrollbar_state: &mut ScrollbarState,
+    overlay: Option<Rect>,
+) -> (Option<ListAction>, f32, std::ops::Range<usize>) {
+    draw_rectangle(x, y, width, height, Color::from_rgba(25, 25, 35, 255));
+
+    draw_text("TITOLI", x + 10.0, y + 30.0, 24.0, WHITE);
+    draw_text(
+        &format!("Selezionati: {}", selected_symbols.len()),
+        x + width - 130.0,
+        y + 30.0,
+        18.0,
+        LIGHTGRAY,
+    );
+
+    draw_line(
+        x,
+        y + 40.0,
+        x + width,
+        y + 40.0,
+        2.0,
+        Color::from_rgba(50, 50, 60, 255),
+    );
+
+    let item_height = 90.0;
+    let item_padding = 5.0;
+    let item_total_h = item_height + item_padding;
+    // Sotto l'intestazione c'è la casella di ricerca (disegnata da draw_search_box)
+    let top = SEARCH_BOX_Y + SEARCH_BOX_H + 8.0;
+    let start_y = y + top;
+    let visible_h = height - top;
+    // Sotto il menu dei risultati la lista non riceve il mouse
+    let mouse_pos = match overlay {
+        Some(rect) if rect.contains(Vec2::from(mouse_position())) => (-1.0, -1.0),
+        _ => mouse_position(),
+    };
+
+    let mut action = None;
+    let mut new_scroll = scroll_offset;
+
+    // Mouse wheel
+    let (_wx, wy) = mouse_wheel();
+    let mouse_in_list = mouse_pos.0 >= x
+        && mouse_pos.0 <= x + width
+        && mouse_pos.1 >= y
+        && mouse_pos.1 <= y + height;
+    if mouse_in_list && !scrollbar_state.dragging {
+        new_scroll -= wy * 60.0; // invertito per feeling naturale
+    }
+    // Clamp anche senza rotella: la lista si accorcia quando si toglie un simbolo
+    let max_scroll = (symbols.len() as f32 * item_total_h - visible_h).max(0.0);
+    new_scroll = new_scroll.clamp(0.0, max_scroll);
+
+    // Solo items visibili
+    let first_idx = (new_scroll / item_total_h).floor().max(0.0) as usize;
+    let last_idx = (((new_scroll + visible_h) / item_total_h).ceil() as usize).min(symbols.len());
+
+    let items_width = width - 30.0;
+
+    for (i, symbol) in symbols.iter().enumerate().take(last_idx).skip(first_idx) {
+        if let Some(stock) = stocks.get(symbol) {
+            let item_y = start_y + (i as f32 * item_total_h) - new_scroll;
+
+            if let Some(item_action) = draw_list_item(
+                stock,
+                &PriceFormat::for_stock(stock, stocks, base_currency),
+                calendar,
+                now,
+                x + 10.0,
+                item_y,
+                items_width - 20.0,
+                item_height,
+                selected_symbols.contains(symbol),
+                mouse_pos,
+            ) {
+                action = Some(item_action);
+            }
+        }
+    }
+
+    // Scrollbar
+    let total_content_h = symbols.len() as f32 * item_total_h;
+    new_scroll = draw_scrollbar(
+        x,
+        start_y,
+        width,
+        visible_h,
+        new_scroll,
+        total_content_h,
+        visible_h,
+        scrollbar_state,
+    );
+
+    (action, new_scroll, first_idx.min(last_idx)..last_idx)
+}
+
+// ────────────────────────────────────────────────
+// Mini-grafico – usa valori precalcolati
+// ────────────────────────────────────────────────
+
+// Pulsanti 1D/5D/…/MAX tra intestazione e grafico; ritorna l'intervallo cliccato
+fn draw_range_buttons(
+    current: ChartRange,
+    x: f32,
+    y: f32,
+    width: f32,
+    mouse_pos: (f32, f32),
+) -> Option<ChartRange> {
+    let (mx, my) = mouse_pos;
+    let gap = 3.0;
+    let bw = ((width - gap * 6.0) / 7.0).min(34.0);
+    let bh = 16.0;
+    let mut clicked = None;
+
+    for (i, range) in ChartRange::ALL.iter().enumerate() {
+        let bx = x + i as f32 * (bw + gap);
+        let is_hovered = mx >= bx && mx <= bx + bw && my >= y && my <= y + bh;
+        let is_current = *range == current;
+
+        let bg = if is_current {
+            Color::from_rgba(50, 100, 200, 255)
+        } else if is_hovered {
+            Color::from_rgba(60, 60, 80, 255)
+        } else {
+            Color::from_rgba(40, 40, 50, 255)
+        };
+        draw_rectangle(bx, y, bw, bh, bg);
+
+        let label = range.label();
+        let tw = measure_text(label, None, 13, 1.0).width;
+        draw_text(label, bx + (bw - tw) / 2.0, y + 12.0, 13.0, WHITE);
+
+        if is_hovered && !is_current && is_mouse_button_pressed(MouseButton::Left) {
+            clicked = Some(*range);
+        }
+    }
+
+    clicked
+}
+
+// Ritorna il nuovo intervallo se l'utente ne ha scelto uno diverso
+#[allow(clippy::too_many_arguments)]
+fn draw_mini_chart(
+    stock: &StockData,
+    price_format: &PriceFormat,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    mouse_pos: (f32, f32),
+) -> Option<ChartRange> {
+    draw_rectangle(x, y, width, height, Color::from_rgba(30, 30, 40, 255));
+    draw_rectangle_lines(x, y, width, height, 2.0, Color::from_rgba(50, 50, 60, 255));
+
+    draw_text(&stock.symbol, x + 15.0, y + 28.0, 24.0, WHITE);
+    let price_label = price_format.format(stock.current_price);
+    draw_text(&price_label, x + 15.0, y + 52.0, 20.0, LIGHTGRAY);
+
+    if let Some(ext) = &stock.extended {
+        let pw = measure_text(&price_label, None, 20, 1.0).width;
+        draw_text(
+            &format!(
+                "{} {} ({:+.2}%)",
+                extended_label(ext.phase),
+                price_format.format(ext.price),
+                ext.change_percent
+            ),
+            x + 25.0 + pw,
+            y + 52.0,
+            15.0,
+            EXTENDED_COLOR,
+        );
+    }
+
+    let ch_color = if stock.change_percent >= 0.0 {
+        Color::from_rgba(0, 200, 100, 255)
+    } else {
+        Color::from_rgba(220, 50, 50, 255)
+    };
+    draw_text(
+        &format!("{:+.2}%", stock.change_percent),
+        x + width - 90.0,
+        y + 32.0,
+        20.0,
+        ch_color,
+    );
+
+    if stock.change_range_percent != 0.0 {
+        draw_text(
+            &format!(
+                "{}: {:+.2}%",
+                stock.range.label(),
+                stock.change_range_percent
+            ),
+            x + width - 90.0,
+            y + 52.0,
+            16.0,
+            Color::from_rgba(100, 150, 255, 255),
+        );
+    }
+
+    let chart_x = x + 70.0;
+    let chart_y = y + 75.0;
+    let chart_w = width - 90.0;
+    let chart_h = height - 105.0;
+
+    let new_range = draw_range_buttons(stock.range, chart_x, y + 57.0, chart_w, mouse_pos);
+
+    if stock.live.len() < 2 && stock.history.len() < 2 {
+        draw_text(
+            "Caricamento...",
+            x + width / 2.0 - 50.0,
+            y + height / 2.0 + 20.0,
+            16.0,
+            GRAY,
+        );
+        return new_range;
+    }
+
+    // ── Layer storico (blu) ───────────────────────────────────
+    let candles = &stock.history;
+    if candles.len() >= 2 && stock.hist_max > stock.hist_min {
+        let range = stock.hist_max - stock.hist_min;
+        let padding = range * 0.08;
+        let min_val = stock.hist_min - padding;
+        let max_val = stock.hist_max + padding;
+        let val_range = max_val - min_val;
+
+        let blue = Color::from_rgba(100, 150, 255, 150);
+        let mut fill_blue = blue;
+        fill_blue.a = 0.1;
+
+        let last = (candles.len() - 1) as f32;
+
+        // volumi: barre tenui sul fondo (20% dell'altezza)
+        let max_volume = candles.iter().map(|c| c.volume).max().unwrap_or(0);
+        if max_volume > 0 {
+            let bar_w = (chart_w / candles.len() as f32).max(1.0);
+            for (i, c) in candles.iter().enumerate() {
+                let bar_h = (c.volume as f32 / max_volume as f32) * chart_h * 0.2;
+                draw_rectangle(
+                    chart_x + (i as f32 / last) * chart_w - bar_w / 2.0,
+                    chart_y + chart_h - bar_h,
+                    bar_w,
+                    bar_h,
+                    Color::from_rgba(100, 150, 255, 40),
+                );
+            }
+        }
+
+        // Tratti di pre/post-market: linea viola più sottile e senza riempimento
+        let is_extended = |c: &Candle| {
+            stock.range.is_intraday()
+                && stock
+                    .session
+                    .as_ref()
+                    .is_some_and(|s| s.phase_at(c.time) != MarketStatus::Open)
+        };
+
+        for i in 0..candles.len() - 1 {
+            let x1 = chart_x + (i as f32 / last) * chart_w;
+            let y1 = chart_y + chart_h - ((candles[i].close - min_val) / val_range) * chart_h;
+            let x2 = chart_x + ((i + 1) as f32 / last) * chart_w;
+            let y2 = chart_y + chart_h - ((candles[i + 1].close - min_val) / val_range) * chart_h;
+
+            if is_extended(&candles[i + 1]) {
+                let mut ext = EXTENDED_COLOR;
+                ext.a = 0.6;
+                draw_line(x1, y1, x2, y2, 1.5, ext);
+                continue;
+            }
+
+            draw_triangle(
+                vec2(x1, y1),
+                vec2(x2, y2),
+                vec2(x2, chart_y + chart_h),
+                fill_blue,
+            );
+            draw_triangle(
+                vec2(x1, y1),
+                vec2(x1, chart_y + chart_h),
+                vec2(x2, chart_y + chart_h),
+                fill_blue,
+            );
+            draw_line(x1, y1, x2, y2, 2.2, blue);
+        }
+
+        // etichette sinistra (blu)
+        for i in 0..3 {
+            let frac = i as f32 / 2.0;
+            let price = max_val - frac * val_range;
+            let gy = chart_y + frac * chart_h;
+            draw_text(
+                &price_format.format(price),
+                x + 5.0,
+                gy + 5.0,
+                13.0,
+                Color::from_rgba(100, 150, 255, 180),
+            );
+        }
+    }
+
+    // ── Layer corrente (verde/rosso) ───────────────────────────
+    let live = &stock.live;
+    if live.max > live.min {
+        let range = live.max - live.min;
+        let padding = range * 0.08;
+        let min_val = live.min - padding;
+        let max_val = live.max + padding;
+        let val_range = max_val - min_val;
+
+        let line_color = if stock.change_percent >= 0.0 {
+            Color::from_rgba(0, 220, 120, 255)
+        } else {
+            Color::from_rgba(255, 80, 80, 255)
+        };
+        let mut fill_color = line_color;
+        fill_color.a = 0.25;
+
+        for i in 0..live.len() - 1 {
+            let x1 = chart_x + (i as f32 / (live.len() - 1) as f32) * chart_w;
+            let y1 = chart_y + chart_h - ((live.prices[i] - min_val) / val_range) * chart_h;
+            let x2 = chart_x + ((i + 1) as f32 / (live.len() - 1) as f32) * chart_w;
+            let y2 = chart_y + chart_h - ((live.prices[i + 1] - min_val) / val_range) * chart_h;
+
+            draw_triangle(
+                vec2(x1, y1),
+                vec2(x2, y2),
+                vec2(x2, chart_y + chart_h),
+                fill_color,
+            );
+            draw_triangle(
+                vec2(x1, y1),
+                vec2(x1, chart_y + chart_h),
+                vec2(x2, chart_y + chart_h),
+                fill_color,
+            );
+            draw_line(x1, y1, x2, y2, 3.0, line_color);
+        }
+
+        // etichette destra
+        for i in 0..5 {
+            let frac = i as f32 / 4.0;
+            let price = max_val - frac * val_range;
+            let gy = chart_y + frac * chart_h;
+
+            let txt = price_format.format(price);
+            let tw = measure_text(&txt, None, 13, 1.0).width + 6.0;
+            draw_rectangle(
+                x + width - tw - 8.0,
+                gy - 8.0,
+                tw,
+                14.0,
+                Color::from_rgba(30, 30, 40, 200),
+            );
+            draw_text(&txt, x + width - tw - 5.0, gy + 4.0, 13.0, line_color);
+        }
+
+        // Legenda
+        let ly = y + height - 10.0;
+        draw_rectangle(
+            x + 8.0,
+            ly - 22.0,
+            width - 16.0,
+            24.0,
+            Color::from_rgba(20, 20, 25, 220),
+        );
+        draw_rectangle_lines(
+            x + 8.0,
+            ly - 22.0,
+            width - 16.0,
+            24.0,
+            1.0,
+            Color::from_rgba(50, 50, 60, 255),
+        );
+
+        draw_line(x + 12.0, ly - 10.0, x + 30.0, ly - 10.0, 3.0, line_color);
+        draw_text(
+            &format!(
+                "Ora ({} – {})",
+                price_format.format(live.min),
+                price_format.format(live.max)
+            ),
+            x + 35.0,
+            ly - 5.0,
+            15.0,
+            WHITE,
+        );
+
+        let midx = x + width / 2.0 + 10.0;
+        draw_line(
+            midx,
+            ly - 10.0,
+            midx + 18.0,
+            ly - 10.0,
+            2.2,
+            Color::from_rgba(100, 150, 255, 255),
+        );
+
+        if stock.history.len() >= 2 {
+            draw_text(
+                &format!(
+                    "{} ({} – {})",
+                    stock.range.label(),
+                    price_format.format(stock.hist_min),
+                    price_format.format(stock.hist_max)
+                ),
+                midx + 23.0,
+                ly - 5.0,
+                15.0,
+                Color::from_rgba(150, 170, 200, 255),
+            );
+        } else {
+            draw_text(
+                stock.range.label(),
+                midx + 23.0,
+                ly - 5.0,
+                15.0,
+                Color::from_rgba(150, 170, 200, 255),
+            );
+        }
+    }
+
+    new_range
+}
+
+// ────────────────────────────────────────────────
+// Pannello grafici – con limite altezza
+// ────────────────────────────────────────────────
+
+fn draw_charts_panel(
+    stocks: &HashMap<String, StockData>,
+    base_currency: Option<&str>,
+    selected_symbols: &HashSet<String>,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+) -> Option<(String, ChartRange)> {
+    draw_rectangle(x, y, width, height, Color::from_rgba(20, 20, 30, 255));
+
+    if selected_symbols.is_empty() {
+        draw_text(
+            "Seleziona uno o più titoli dalla lista a sinistra",
+            x + width / 2.0 - 200.0,
+            y + height / 2.0,
+            20.0,
+            GRAY,
+        );
+        return None;
+    }
+
+    let selected: Vec<&String> = selected_symbols.iter().collect();
+    let count = selected.len();
+
+    let cols = if count == 1 {
+        1
+    } else if count <= 4 {
+        2
+    } else {
+        3
+    };
+    let rows = count.div_ceil(cols);
+
+    let padding = 15.0;
+    let max_chart_h = 500.0;
+
+    let avail_w = (width - padding * (cols as f32 + 1.0)) / cols as f32;
+    let avail_h = (height - padding * (rows as f32 + 1.0)) / rows as f32;
+
+    let chart_h = avail_h.min(max_chart_h);
+    let chart_w = avail_w;
+
+    let total_charts_h = rows as f32 * chart_h + padding * (rows as f32 + 1.0);
+    let v_offset = if total_charts_h < height {
+        (height - total_charts_h) / 2.0
+    } else {
+        0.0
+    };
+
+    let mouse_pos = mouse_position();
+    let mut range_change = None;
+
+    for (i, &symbol) in selected.iter().enumerate() {
+        if let Some(stock) = stocks.get(symbol) {
+            let col = i % cols;
+            let row = i / cols;
+
+            let cx = x + padding + col as f32 * (chart_w + padding);
+            let cy = y + padding + v_offset + row as f32 * (chart_h + padding);
+
+            let price_format = PriceFormat::for_stock(stock, stocks, base_currency);
+            if let Some(range) =
+                draw_mini_chart(stock, &price_format, cx, cy, chart_w, chart_h, mouse_pos)
+            {
+                range_change = Some((symbol.clone(), range));
+            }
+        }
+    }
+
+    range_change
+}
+
+// ────────────────────────────────────────────────
+// Supervisore dei worker: cancellazione, pausa, riavvio dopo un panic
+// ────────────────────────────────────────────────
+
+// Granularità con cui i worker in attesa si accorgono di stop e pausa
+const CONTROL_TICK: Duration = Duration::from_millis(200);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+// Attesa massima dei worker alla chiusura della finestra
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Token condiviso da tutti i worker: `cancelled` chiede di terminare,
+// `paused` sospende il polling (lo stream resta connesso)
+#[derive(Debug, Clone, Default)]
+struct WorkerControl {
+    cancelled: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+}
+
+impl WorkerControl {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn toggle_pause(&self) {
+        self.paused.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    // Dorme fino a `duration` ma si sveglia subito se cancellato.
+    // false = il worker deve terminare
+    fn sleep(&self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            thread::sleep(left.min(CONTROL_TICK));
+        }
+    }
+}
+
+type WorkerFn = Arc<dyn Fn(&WorkerControl) + Send + Sync>;
+
+struct Worker {
+    name: String,
+    run: WorkerFn,
+    handle: Option<thread::JoinHandle<()>>,
+    restarts: u32,
+    restart_at: Option<Instant>,
+}
+
+// Possiede i thread dei worker. Un worker che termina normalmente è finito
+// (es. il fetch iniziale); uno che termina con un panic viene riavviato
+// con un ritardo crescente.
+struct Supervisor {
+    control: WorkerControl,
+    workers: Vec<Worker>,
+}
+
+impl Supervisor {
+    fn new() -> Self {
+        Self {
+            control: WorkerControl::default(),
+            workers: Vec::new(),
+        }
+    }
+
+    fn spawn<F>(&mut self, name: &str, run: F)
+    where
+        F: Fn(&WorkerControl) + Send + Sync + 'static,
+    {
+        let run: WorkerFn = Arc::new(run);
+        let handle = self.launch(name, &run);
+        self.workers.push(Worker {
+            name: name.to_string(),
+            run,
+            handle,
+            restarts: 0,
+            restart_at: None,
+        });
+    }
+
+    fn launch(&self, name: &str, run: &WorkerFn) -> Option<thread::JoinHandle<()>> {
+        let run = run.clone();
+        let control = self.control.clone();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || run(&control))
+            .map_err(|err| eprintln!("Worker {} non avviato: {}", name, err))
+            .ok()
+    }
+
+    // Chiamato a ogni frame: raccoglie i worker terminati e riavvia quelli andati in panic
+    fn check(&mut self) {
+        if self.control.is_cancelled() {
+            return;
+        }
+
+        let now = Instant::now();
+        for i in 0..self.workers.len() {
+            let worker = &mut self.workers[i];
+            if worker.handle.as_ref().is_some_and(|h| h.is_finished())
+                && let Some(handle) = worker.handle.take()
+                && handle.join().is_err()
+            {
+                let delay = Duration::from_secs(1 << worker.restarts.min(5)).min(MAX_RESTART_DELAY);
+                eprintln!(
+                    "Worker {} terminato con un panic, riavvio tra {}s",
+                    worker.name,
+                    delay.as_secs()
+                );
+                worker.restarts += 1;
+                worker.restart_at = Some(now + delay);
+            }
+
+            if self.workers[i].restart_at.is_some_and(|t| t <= now) {
+                let handle = self.launch(&self.workers[i].name, &self.workers[i].run);
+                let worker = &mut self.workers[i];
+                worker.handle = handle;
+                worker.restart_at = None;
+            }
+        }
+        self.workers
+            .retain(|w| w.handle.is_some() || w.restart_at.is_some());
+    }
+
+    // Ferma tutti i worker e attende che escano, al massimo per `timeout`:
+    // una richiesta HTTP in corso non deve bloccare la chiusura della finestra
+    fn shutdown(self, timeout: Duration) {
+        self.control.cancel();
+
+        let deadline = Instant::now() + timeout;
+        let mut pending: Vec<(String, thread::JoinHandle<()>)> = self
+            .workers
+            .into_iter()
+            .filter_map(|w| w.handle.map(|h| (w.name, h)))
+            .collect();
+
+        while !pending.is_empty() && Instant::now() < deadline {
+            let (done, running): (Vec<_>, Vec<_>) =
+                pending.into_iter().partition(|(_, h)| h.is_finished());
+            for (_, handle) in done {
+                let _ = handle.join();
+            }
+            pending = running;
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        for (name, _) in pending {
+            eprintln!("Worker {} ancora attivo alla chiusura", name);
+        }
+    }
+}
+
+// ────────────────────────────────────────────────
+// Worker di aggiornamento
+// ────────────────────────────────────────────────
+
+// Esegue `f` su tutti gli elementi con al massimo `limit` thread alla volta:
+// un simbolo lento (timeout) non blocca più quelli successivi.
+fn for_each_bounded<T, F>(items: &[T], limit: usize, f: F)
+where
+    T: Sync,
+    F: Fn(&T) + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let workers = limit.clamp(1, items.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    match items.get(i) {
+                        Some(item) => f(item),
+                        None => break,
+                    }
+                }
+            });
+        }
+    });
+}
+
+// I worker non toccano mai la mappa dei titoli: pubblicano messaggi che il
+// render loop applica all'inizio di ogni frame, senza lock condivisi.
+#[derive(Debug)]
+enum StockUpdate {
+    Quote {
+        symbol: String,
+        result: FetchResult<Quote>,
+        time: DateTime<Utc>,
+    },
+    History {
+        symbol: String,
+        range: ChartRange,
+        result: FetchResult<History>,
+    },
+    Tick(StreamTick),
+    // Fine di un giro di polling (orario mostrato in basso)
+    Polled(DateTime<Utc>),
+    // Risultati della casella di ricerca per il testo `query`
+    Search {
+        query: String,
+        result: FetchResult<Vec<SymbolMatch>>,
+    },
+}
+
+type UpdateSender = Sender<StockUpdate>;
+
+// Ultimo tick dello stream per simbolo, condiviso solo tra stream e polling:
+// finché è recente il polling salta il simbolo
+type StreamActivity = Mutex<HashMap<String, DateTime<Utc>>>;
+
+fn update_quotes(
+    updates: &UpdateSender,
+    provider: &dyn MarketDataProvider,
+    symbols: &[String],
+    concurrency: usize,
+    store: Option<&TickStore>,
+    ctl: &WorkerControl,
+) -> HashMap<String, TradingSession> {
+    let now = Utc::now();
+    let sessions = Mutex::new(HashMap::new());
+    let publish = |symbol: String, result: FetchResult<Quote>| {
+        if let Ok(quote) = &result {
+            if let Some(store) = store {
+                store.append(&symbol, quote.market_time.unwrap_or(now), quote.price);
+            }
+            if let (Some(session), Ok(mut sessions)) = (&quote.session, sessions.lock()) {
+                sessions.insert(symbol.clone(), session.clone());
+            }
+        }
+        let _ = updates.send(StockUpdate::Quote {
+            symbol,
+            result,
+            time: now,
+        });
+    };
+
+    if provider.supports_batch() {
+        for (symbol, result) in provider.fetch_quotes(symbols, concurrency) {
+            publish(symbol, result);
+        }
+    } else {
+        for_each_bounded(symbols, concurrency, |symbol| {
+            if !ctl.is_cancelled() {
+                publish(symbol.clone(), provider.fetch_quote(symbol));
+            }
+        });
+    }
+
+    sessions.into_inner().unwrap_or_default()
+}
+
+fn update_histories(
+    updates: &UpdateSender,
+    provider: &dyn MarketDataProvider,
+    jobs: &[(String, ChartRange)],
+    concurrency: usize,
+    ctl: &WorkerControl,
+) {
+    for_each_bounded(jobs, concurrency, |(symbol, range)| {
+        if ctl.is_cancelled() {
+            return;
+        }
+        let _ = updates.send(StockUpdate::History {
+            symbol: symbol.clone(),
+            range: *range,
+            result: provider.fetch_history(symbol, *range),
+        });
+    });
+}
+
+// ────────────────────────────────────────────────
+// Orari di borsa e calendario delle festività
+// ────────────────────────────────────────────────
+
+const US_TIMEZONE: &str = "America/New_York";
+// Oltre questo numero di giorni senza sedute si rinuncia a cercare la prossima
+const MAX_CLOSED_DAYS: i64 = 14;
+
+// Festività NYSE/Nasdaq calcolate dalle regole della borsa, per qualsiasi
+// anno. Chiusure straordinarie (lutti nazionali, uragani) vanno in --holidays.
+fn is_nyse_holiday(date: NaiveDate) -> bool {
+    let year = date.year();
+    let nth = |month, weekday, n| NaiveDate::from_weekday_of_month_opt(year, month, weekday, n);
+    // Festività fissa di sabato: chiuso il venerdì prima; di domenica: il lunedì dopo
+    let observed = |month, day| {
+        let date = NaiveDate::from_ymd_opt(year, month, day)?;
+        match date.weekday() {
+            Weekday::Sat => date.pred_opt(),
+            Weekday::Sun => date.succ_opt(),
+            _ => Some(date),
+        }
+    };
+    let last_monday_of_may = NaiveDate::from_ymd_opt(year, 5, 31)
+        .map(|d| d - chrono::Duration::days(d.weekday().num_days_from_monday() as i64));
+
+    // Capodanno di sabato non viene recuperato il 31 dicembre (regola NYSE)
+    let new_year = NaiveDate::from_ymd_opt(year, 1, 1)
+        .filter(|d| d.weekday() != Weekday::Sat)
+        .and_then(|_| observed(1, 1));
+    let holidays = [
+        new_year,
+        nth(1, Weekday::Mon, 3),
+        nth(2, Weekday::Mon, 3),
+        easter_sunday(year).map(|d| d - chrono::Duration::days(2)),
+        last_monday_of_may,
+        if year >= 2022 { observed(6, 19) } else { None },
+        observed(7, 4),
+        nth(9, Weekday::Mon, 1),
+        nth(11, Weekday::Thu, 4),
+        observed(12, 25),
+    ];
+    holidays.contains(&Some(date))
+}
+
+// Domenica di Pasqua nel calendario gregoriano (algoritmo di Meeus/Jones/Butcher)
+fn easter_sunday(year: i32) -> Option<NaiveDate> {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MarketStatus {
+    Pre,
+    Open,
+    Post,
+    Closed,
+}
+
+impl MarketStatus {
+    fn label(self) -> &'static str {
+        match self {
+            MarketStatus::Pre => "PRE",
+            MarketStatus::Open => "APERTO",
+            MarketStatus::Post => "POST",
+            MarketStatus::Closed => "CHIUSO",
+        }
+    }
+}
+
+// Una festività vale per tutte le borse o solo per quella col fuso indicato
+#[derive(Debug)]
+struct MarketCalendar {
+    holidays: Vec<(NaiveDate, Option<String>)>,
+}
+
+impl MarketCalendar {
+    // Festività USA calcolate; il file aggiunge righe "AAAA-MM-GG[,fuso]",
+    // es. "2026-12-26,Europe/London"
+    fn load(path: Option<&str>) -> Self {
+        let mut holidays: Vec<(NaiveDate, Option<String>)> = Vec::new();
+
+        if let Some(path) = path {
+            match fs::read_to_string(path) {
+                Ok(body) => holidays.extend(body.lines().filter_map(|line| {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        return None;
+                    }
+                    let (date, timezone) = match line.split_once(',') {
+                        Some((date, tz)) => (date, Some(tz.trim().to_string())),
+                        None => (line, None),
+                    };
+                    Some((
+                        NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()?,
+                        timezone,
+                    ))
+                })),
+                Err(err) => eprintln!("Festività {} ignorate: {}", path, err),
+            }
+        }
+
+        Self { holidays }
+    }
+
+    fn is_trading_day(&self, date: NaiveDate, timezone: &str) -> bool {
+        if matches!(date.weekday(), Weekday::Sat | Weekday::Sun) {
+            return false;
+        }
+        if timezone == US_TIMEZONE && is_nyse_holiday(date) {
+            return false;
+        }
+        !self
+            .holidays
+            .iter()
+            .any(|(d, tz)| *d == date && tz.as_deref().is_none_or(|tz| tz == timezone))
+    }
+}
+
+// Sedute di una giornata (pre, regolare, post) da `currentTradingPeriod`.
+// Per i giorni successivi gli stessi orari vengono spostati di 24 ore.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TradingSession {
+    pre: (DateTime<Utc>, DateTime<Utc>),
+    regular: (DateTime<Utc>, DateTime<Utc>),
+    post: (DateTime<Utc>, DateTime<Utc>),
+    // Fuso e offset della borsa: servono per la data locale delle festività
+    timezone: String,
+    gmtoffset: i64,
+}
+
+impl TradingSession {
+    // None per le crypto, che scambiano 24/7
+    fn from_meta(meta: &ChartMeta) -> Option<Self> {
+        if meta.instrument_type.as_deref() == Some("CRYPTOCURRENCY") {
+            return None;
+        }
+        let periods = meta.current_trading_period.as_ref()?;
+        let window = |p: &TradingPeriod| {
+            Some((
+                DateTime::from_timestamp(p.start, 0)?,
+                DateTime::from_timestamp(p.end, 0)?,
+            ))
+        };
+
+        Some(Self {
+            pre: window(&periods.pre)?,
+            regular: window(&periods.regular)?,
+            post: window(&periods.post)?,
+            timezone: meta
+                .exchange_timezone_name
+                .clone()
+                .unwrap_or_else(|| periods.regular.timezone.clone()),
+            gmtoffset: periods.regular.gmtoffset,
+        })
+    }
+
+    // Seduta a cui appartiene un istante, riportando gli orari al suo giorno
+    // (festività ignorate: serve per classificare candele già scambiate)
+    fn phase_at(&self, time: DateTime<Utc>) -> MarketStatus {
+        let days = (time - self.pre.0).num_seconds().div_euclid(86_400);
+        let shift = chrono::Duration::days(days);
+        if time < self.regular.0 + shift {
+            MarketStatus::Pre
+        } else if time < self.regular.1 + shift {
+            MarketStatus::Open
+        } else if time < self.post.1 + shift {
+            MarketStatus::Post
+        } else {
+            MarketStatus::Closed
+        }
+    }
+
+    // Stato della borsa in `now` e istante del prossimo cambio di stato
+    fn status(
+        &self,
+        now: DateTime<Utc>,
+        calendar: &MarketCalendar,
+    ) -> (MarketStatus, DateTime<Utc>) {
+        // Orari vecchi (es. dallo snapshot): si parte dal giorno corrente
+        let first_day = (now - self.post.1).num_days().max(0);
+
+        for day in first_day..first_day + MAX_CLOSED_DAYS {
+            let shift = chrono::Duration::days(day);
+            let local_date =
+                (self.regular.0 + shift + chrono::Duration::seconds(self.gmtoffset)).date_naive();
+            if !calendar.is_trading_day(local_date, &self.timezone) {
+                continue;
+            }
+
+            let pre = self.pre.0 + shift;
+            let (open, close) = (self.regular.0 + shift, self.regular.1 + shift);
+            let post = self.post.1 + shift;
+            if now >= post {
+                continue;
+            }
+            return if now < pre {
+                (MarketStatus::Closed, pre)
+            } else if now < open {
+                (MarketStatus::Pre, open)
+            } else if now < close {
+                (MarketStatus::Open, close)
+            } else {
+                (MarketStatus::Post, post)
+            };
+        }
+
+        (MarketStatus::Closed, now + chrono::Duration::days(1))
+    }
+}
+
+// "2h 05m", "12m 30s", "3g 4h"
+fn format_countdown(left: chrono::Duration) -> String {
+    let secs = left.num_seconds().max(0);
+    let (days, hours, mins) = (secs / 86_400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}g {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {:02}m", hours, mins)
+    } else {
+        format!("{}m {:02}s", mins, secs % 60)
+    }
+}
+
+// ────────────────────────────────────────────────
+// Scheduler unico delle richieste, con priorità per simbolo
+// ────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AssetClass {
+    Crypto,
+    Fx,
+    Index,
+    Equity,
+}
+
+impl AssetClass {
+    // Dalle convenzioni dei simboli Yahoo: BTC-USD, EURUSD=X, ^GSPC, ES=F
+    fn of(symbol: &str) -> Self {
+        if ["-USD", "-EUR", "-USDT", "-BTC"]
+            .iter()
+            .any(|suffix| symbol.ends_with(suffix))
+        {
+            AssetClass::Crypto
+        } else if symbol.ends_with("=X") {
+            AssetClass::Fx
+        } else if symbol.starts_with('^') || symbol.ends_with("=F") {
+            AssetClass::Index
+        } else {
+            AssetClass::Equity
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PollIntervals {
+    crypto: Duration,
+    fx: Duration,
+    index: Duration,
+    equity: Duration,
+    history: Duration,
+    // I simboli fuori schermo si aggiornano `offscreen_factor` volte più
+    // di rado; quelli selezionati il doppio più spesso
+    offscreen_factor: f64,
+}
+
+impl PollIntervals {
+    fn quote(&self, class: AssetClass) -> Duration {
+        match class {
+            AssetClass::Crypto => self.crypto,
+            AssetClass::Fx => self.fx,
+            AssetClass::Index => self.index,
+            AssetClass::Equity => self.equity,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum JobKind {
+    Quote,
+    History,
+}
+
+// Ordinati per scadenza (primo campo): in un BinaryHeap con Reverse
+// in cima c'è sempre il job più urgente
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ScheduledJob {
+    due: Instant,
+    kind: JobKind,
+    symbol: String,
+}
+
+#[derive(Debug)]
+struct JobState {
+    due: Instant,
+    last_run: Option<Instant>,
+}
+
+// Inviati dal render loop allo scheduler
+#[derive(Debug)]
+enum SchedulerCommand {
+    // Simboli selezionati e visibili nella lista: salgono di priorità
+    Focus {
+        selected: HashSet<String>,
+        visible: HashSet<String>,
+    },
+    // Intervallo del grafico cambiato: storico da ricaricare subito
+    Reload {
+        symbol: String,
+        range: ChartRange,
+    },
+    // Simbolo fuori dalla lista (il cambio verso la valuta base): solo quote
+    TrackQuote {
+        symbol: String,
+    },
+    // Simbolo aggiunto o tolto dalla lista durante l'esecuzione
+    Add {
+        symbol: String,
+        range: ChartRange,
+    },
+    Remove {
+        symbol: String,
+    },
+}
+
+// Una sola coda per quote e storico di tutti i simboli. Quando cambia una
+// scadenza si aggiunge un nuovo elemento alla coda: quello vecchio resta
+// lì e viene scartato all'estrazione perché non coincide più con `jobs`.
+#[derive(Debug)]
+struct Scheduler {
+    queue: BinaryHeap<Reverse<ScheduledJob>>,
+    jobs: HashMap<(String, JobKind), JobState>,
+    ranges: HashMap<String, ChartRange>,
+    selected: HashSet<String>,
+    visible: HashSet<String>,
+    intervals: PollIntervals,
+    // Orari di borsa imparati dalle quote: a mercato chiuso niente polling
+    sessions: HashMap<String, TradingSession>,
+    calendar: Arc<MarketCalendar>,
+    // Orologio per lo stato della borsa: in replay è quello della sessione
+    clock: Tape,
+}
+
+impl Scheduler {
+    // Tutto in scadenza subito: è il fetch iniziale
+    fn new(
+        ranges: Vec<(String, ChartRange)>,
+        intervals: PollIntervals,
+        calendar: Arc<MarketCalendar>,
+        clock: Tape,
+    ) -> Self {
+        let mut scheduler = Self {
+            queue: BinaryHeap::new(),
+            jobs: HashMap::new(),
+            ranges: HashMap::new(),
+            selected: HashSet::new(),
+            visible: HashSet::new(),
+            intervals,
+            sessions: HashMap::new(),
+            calendar,
+            clock,
+        };
+
+        for (symbol, range) in ranges {
+            scheduler.add(&symbol, range);
+        }
+        scheduler
+    }
+
+    fn add(&mut self, symbol: &str, range: ChartRange) {
+        let now = Instant::now();
+        self.schedule(symbol, JobKind::Quote, now);
+        self.schedule(symbol, JobKind::History, now);
+        self.ranges.insert(symbol.to_string(), range);
+    }
+
+    // Gli elementi già in coda restano, ma senza job corrispondente
+    // vengono scartati all'estrazione
+    fn remove(&mut self, symbol: &str) {
+        for kind in [JobKind::Quote, JobKind::History] {
+            self.jobs.remove(&(symbol.to_string(), kind));
+        }
+        self.ranges.remove(symbol);
+        self.sessions.remove(symbol);
+        self.selected.remove(symbol);
+        self.visible.remove(symbol);
+    }
+
+    fn schedule(&mut self, symbol: &str, kind: JobKind, due: Instant) {
+        self.jobs
+            .entry((symbol.to_string(), kind))
+            .and_modify(|job| job.due = due)
+            .or_insert(JobState {
+                due,
+                last_run: None,
+            });
+        self.queue.push(Reverse(ScheduledJob {
+            due,
+            kind,
+            symbol: symbol.to_string(),
+        }));
+    }
+
+    fn interval(&self, symbol: &str, kind: JobKind) -> Duration {
+        let base = match kind {
+            JobKind::Quote => self.intervals.quote(AssetClass::of(symbol)),
+            JobKind::History => self.intervals.history,
+        };
+        if self.selected.contains(symbol) {
+            base / 2
+        } else if self.visible.contains(symbol) {
+            base
+        } else {
+            base.mul_f64(self.intervals.offscreen_factor)
+        }
+    }
+
+    // Prossima esecuzione dopo `from`; a borsa chiusa si aspetta la riapertura
+    // (il pre-market conta come aperto)
+    fn next_run(&self, symbol: &str, kind: JobKind, from: Instant) -> Instant {
+        let next = from + self.interval(symbol, kind);
+        let Some(session) = self.sessions.get(symbol) else {
+            return next;
+        };
+
+        let now = self.clock.now();
+        match session.status(now, &self.calendar) {
+            (MarketStatus::Closed, opens_at) => {
+                let wait = self.clock.real_duration(opens_at - now);
+                next.max(Instant::now() + wait)
+            }
+            _ => next,
+        }
+    }
+
+    fn set_sessions(&mut self, sessions: HashMap<String, TradingSession>) {
+        for (symbol, session) in sessions {
+            let changed = self.sessions.get(&symbol) != Some(&session);
+            self.sessions.insert(symbol.clone(), session);
+            if !changed {
+                continue;
+            }
+            // Borsa appena scoperta chiusa: rimanda anche i job già in coda
+            for kind in [JobKind::Quote, JobKind::History] {
+                let Some(job) = self.jobs.get(&(symbol.clone(), kind)) else {
+                    continue;
+                };
+                let due = job.due;
+                let next = self.next_run(&symbol, kind, Instant::now()).max(due);
+                if next > due {
+                    self.schedule(&symbol, kind, next);
+                }
+            }
+        }
+    }
+
+    fn range(&self, symbol: &str) -> ChartRange {
+        self.ranges.get(symbol).copied().unwrap_or_default()
+    }
+
+    // I simboli che salgono di priorità anticipano la prossima scadenza;
+    // quelli che scendono la allungano alla prossima esecuzione
+    fn set_focus(&mut self, selected: HashSet<String>, visible: HashSet<String>) {
+        self.selected = selected;
+        self.visible = visible;
+
+        let focused: Vec<String> = self.selected.union(&self.visible).cloned().collect();
+        for symbol in focused {
+            for kind in [JobKind::Quote, JobKind::History] {
+                let Some(job) = self.jobs.get(&(symbol.clone(), kind)) else {
+                    continue;
+                };
+                let due = job
+                    .last_run
+                    .map_or_else(Instant::now, |t| self.next_run(&symbol, kind, t));
+                if due < job.due {
+                    self.schedule(&symbol, kind, due);
+                }
+            }
+        }
+    }
+
+    fn track_quote(&mut self, symbol: &str) {
+        if !self
+            .jobs
+            .contains_key(&(symbol.to_string(), JobKind::Quote))
+        {
+            self.schedule(symbol, JobKind::Quote, Instant::now());
+        }
+    }
+
+    fn set_range(&mut self, symbol: &str, range: ChartRange) {
+        self.ranges.insert(symbol.to_string(), range);
+    }
+
+    // Registra l'esecuzione (anche fuori coda) e fissa la prossima scadenza
+    fn mark_run(&mut self, symbol: &str, kind: JobKind, now: Instant) {
+        let next = self.next_run(symbol, kind, now);
+        self.schedule(symbol, kind, next);
+        if let Some(job) = self.jobs.get_mut(&(symbol.to_string(), kind)) {
+            job.last_run = Some(now);
+        }
+    }
+
+    fn is_current(&self, job: &ScheduledJob) -> bool {
+        self.jobs
+            .get(&(job.symbol.clone(), job.kind))
+            .is_some_and(|state| state.due == job.due)
+    }
+
+    // Prima scadenza tra i tipi di job che possono partire (`ready`)
+    fn next_due(&mut self, ready: impl Fn(JobKind) -> bool) -> Option<Instant> {
+        while let Some(Reverse(job)) = self.queue.peek() {
+            if self.is_current(job) {
+                break;
+            }
+            self.queue.pop();
+        }
+        self.queue
+            .iter()
+            .map(|Reverse(job)| job)
+            .filter(|job| ready(job.kind) && self.is_current(job))
+            .map(|job| job.due)
+            .min()
+    }
+
+    // Job scaduti, al massimo `limit(kind)` per tipo: gli altri restano in
+    // coda con la stessa scadenza e partono per primi al giro successivo
+    fn take_due(&mut self, now: Instant, limit: impl Fn(JobKind) -> usize) -> Vec<ScheduledJob> {
+        let mut due = Vec::new();
+        let mut deferred = Vec::new();
+        let (mut quotes, mut histories) = (0, 0);
+        while let Some(Reverse(job)) = self.queue.peek() {
+            if job.due > now {
+                break;
+            }
+            let Some(Reverse(job)) = self.queue.pop() else {
+                break;
+            };
+            if !self.is_current(&job) {
+                continue;
+            }
+            let taken = match job.kind {
+                JobKind::Quote => &mut quotes,
+                JobKind::History => &mut histories,
+            };
+            if *taken >= limit(job.kind) {
+                deferred.push(Reverse(job));
+                continue;
+            }
+            *taken += 1;
+            self.mark_run(&job.symbol, job.kind, now);
+            due.push(job);
+        }
+        self.queue.extend(deferred);
+        due
+    }
+}
+
+// Esegue i job in scadenza: quote e storico in parallelo tra loro, ognuno
+// con la propria concorrenza limitata
+#[allow(clippy::too_many_arguments)]
+fn start_scheduler(
+    supervisor: &mut Supervisor,
+    updates: UpdateSender,
+    provider: Arc<dyn MarketDataProvider>,
+    scheduler: Scheduler,
+    concurrency: usize,
+    store: Option<Arc<TickStore>>,
+    stream_activity: Arc<StreamActivity>,
+    commands: Receiver<SchedulerCommand>,
+) {
+    // Condivisi tra un riavvio e l'altro del worker
+    let scheduler = Mutex::new(scheduler);
+    let commands = Mutex::new(commands);
+
+    supervisor.spawn("scheduler", move |ctl| {
+        let mut sched = scheduler.lock().unwrap_or_else(|e| e.into_inner());
+        let commands = commands.lock().unwrap_or_else(|e| e.into_inner());
+        let (updates, provider, store) = (&updates, provider.as_ref(), store.as_deref());
+
+        // I batch girano in thread separati, al massimo uno per tipo: intanto
+        // il loop legge i comandi e fa partire i job dell'altro tipo
+        thread::scope(|scope| {
+            let mut quote_batch = None;
+            let mut history_batch = None;
+
+            while !ctl.is_cancelled() {
+                if let Some(sessions) = join_finished(&mut quote_batch) {
+                    sched.set_sessions(sessions);
+                }
+                join_finished(&mut history_batch);
+                let (quotes_idle, histories_idle) =
+                    (quote_batch.is_none(), history_batch.is_none());
+                let ready = |kind| match kind {
+                    JobKind::Quote => quotes_idle,
+                    JobKind::History => histories_idle,
+                };
+
+                let wait = match sched.next_due(ready) {
+                    Some(due) if !ctl.is_paused() => due
+                        .saturating_duration_since(Instant::now())
+                        .min(CONTROL_TICK),
+                    _ => CONTROL_TICK,
+                };
+
+                match commands.recv_timeout(wait) {
+                    Ok(first) => {
+                        // Le ricariche chieste dall'utente partono anche in pausa
+                        let mut reloads: Vec<(String, ChartRange)> = Vec::new();
+                        for command in std::iter::once(first).chain(commands.try_iter()) {
+                            match command {
+                                SchedulerCommand::Focus { selected, visible } => {
+                                    sched.set_focus(selected, visible)
+                                }
+                                SchedulerCommand::Reload { symbol, range } => {
+                                    sched.set_range(&symbol, range);
+                                    sched.mark_run(&symbol, JobKind::History, Instant::now());
+                                    reloads.retain(|(s, _)| *s != symbol);
+                                    reloads.push((symbol, range));
+                                }
+                                SchedulerCommand::TrackQuote { symbol } => {
+                                    sched.track_quote(&symbol)
+                                }
+                                SchedulerCommand::Add { symbol, range } => {
+                                    sched.add(&symbol, range)
+                                }
+                                SchedulerCommand::Remove { symbol } => {
+                                    sched.remove(&symbol);
+                                    reloads.retain(|(s, _)| *s != symbol);
+                                }
+                            }
+                        }
+                        if !reloads.is_empty() {
+                            scope.spawn(move || {
+                                update_histories(updates, provider, &reloads, concurrency, ctl)
+                            });
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        ctl.sleep(wait);
+                    }
+                }
+
+                if ctl.is_paused() {
+                    continue;
+                }
+                // Storico a gruppi di `concurrency`: un avvio con decine di
+                // simboli non occupa il provider a lungo davanti ai job più urgenti
+                let jobs = sched.take_due(Instant::now(), |kind| match kind {
+                    JobKind::Quote if quotes_idle => usize::MAX,
+                    JobKind::History if histories_idle => concurrency,
+                    _ => 0,
+                });
+                if jobs.is_empty() {
+                    continue;
+                }
+
+                // Quote saltate per i simboli che lo stream ha aggiornato di recente
+                let now = Utc::now();
+                let seen = stream_activity
+                    .lock()
+                    .map(|seen| seen.clone())
+                    .unwrap_or_default();
+                let mut quotes: Vec<String> = Vec::new();
+                let mut histories: Vec<(String, ChartRange)> = Vec::new();
+                for job in jobs {
+                    match job.kind {
+                        JobKind::Quote => {
+                            let interval = chrono::Duration::from_std(
+                                sched.interval(&job.symbol, JobKind::Quote),
+                            )
+                            .unwrap_or_default();
+                            if seen.get(&job.symbol).is_none_or(|t| now - *t >= interval) {
+                                quotes.push(job.symbol);
+                            }
+                        }
+                        JobKind::History => {
+                            let range = sched.range(&job.symbol);
+                            histories.push((job.symbol, range));
+                        }
+                    }
+                }
+
+                if !histories.is_empty() {
+                    history_batch = Some(scope.spawn(move || {
+                        update_histories(updates, provider, &histories, concurrency, ctl)
+                    }));
+                }
+                if !quotes.is_empty() {
+                    quote_batch = Some(scope.spawn(move || {
+                        let sessions =
+                            update_quotes(updates, provider, &quotes, concurrency, store, ctl);
+                        let _ = updates.send(StockUpdate::Polled(Utc::now()));
+                        sessions
+                    }));
+                }
+            }
+        });
+    });
+}
+
+// Risultato di un batch concluso, None se ancora in corso. Il panic di un
+// batch fa ripartire lo scheduler come quello del worker stesso.
+fn join_finished<T>(batch: &mut Option<thread::ScopedJoinHandle<'_, T>>) -> Option<T> {
+    if !batch.as_ref().is_some_and(|handle| handle.is_finished()) {
+        return None;
+    }
+    match batch.take()?.join() {
+        Ok(value) => Some(value),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+// ────────────────────────────────────────────────
+// Streaming WebSocket (tick in tempo reale, polling come fallback)
+// ────────────────────────────────────────────────
+
+#[derive(Debug, Clone)]
+struct StreamTick {
+    symbol: String,
+    price: f32,
+    change_percent: Option<f32>,
+    time: DateTime<Utc>,
+}
+
+// Tick in chiaro, utile con un server WebSocket locale di test:
+// {"symbol": "BTC-USD", "price": 64000.5, "changePercent": 1.2, "time": 1700000000000}
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonTick {
+    #[serde(alias = "id")]
+    symbol: String,
+    price: f32,
+    change_percent: Option<f32>,
+    // millisecondi epoch
+    time: Option<i64>,
+}
+
+// Formato attuale di Yahoo: {"type": "pricing", "message": "<protobuf base64>"}
+#[derive(Debug, Deserialize)]
+struct StreamEnvelope {
+    message: String,
+}
+
+fn parse_stream_message(msg: &Message) -> Option<StreamTick> {
+    match msg {
+        Message::Text(text) => {
+            let text = text.trim();
+            if let Ok(tick) = serde_json::from_str::<JsonTick>(text) {
+                return Some(StreamTick {
+                    symbol: tick.symbol,
+                    price: tick.price,
+                    change_percent: tick.change_percent,
+                    time: tick
+                        .time
+                        .and_then(DateTime::from_timestamp_millis)
+                        .unwrap_or_else(Utc::now),
+                });
+            }
+
+            let payload = serde_json::from_str::<StreamEnvelope>(text)
+                .map(|env| env.message)
+                .unwrap_or_else(|_| text.to_string());
+            let bytes = BASE64.decode(payload.as_bytes()).ok()?;
+            decode_pricing_data(&bytes)
+        }
+        Message::Binary(bytes) => decode_pricing_data(bytes),
+        _ => None,
+    }
+}
+
+// Decoder minimale del messaggio protobuf PricingData di Yahoo:
+// 1 = id (string), 2 = price (float), 3 = time (sint64, ms), 8 = changePercent (float)
+fn decode_pricing_data(buf: &[u8]) -> Option<StreamTick> {
+    fn varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *buf.get(*pos)?;
+            *pos += 1;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
 
-        let line_color = if stock.change_percent >= 0.0 {
-            Color::from_rgba(0, 220, 120, 255)
-        } else {
-            Color::from_rgba(255, 80, 80, 255)
-        };
-        let mut fill_color = line_color;
-        fill_color.a = 0.25;
+    let mut pos = 0;
+    let mut symbol = None;
+    let mut price = None;
+    let mut time = None;
+    let mut change_percent = None;
 
-        for i in 0..stock.prices.len() - 1 {
-            let x1 = chart_x + (i as f32 / (stock.prices.len() - 1) as f32) * chart_w;
-            let y1 = chart_y + chart_h - ((stock.prices[i] - min_val) / val_range) * chart_h;
-            let x2 = chart_x + ((i + 1) as f32 / (stock.prices.len() - 1) as f32) * chart_w;
-            let y2 = chart_y + chart_h - ((stock.prices[i + 1] - min_val) / val_range) * chart_h;
+    while pos < buf.len() {
+        let key = varint(buf, &mut pos)?;
+        let (field, wire_type) = (key >> 3, key & 0x7);
 
-            draw_triangle(
-                vec2(x1, y1),
-                vec2(x2, y2),
-                vec2(x2, chart_y + chart_h),
-                fill_color,
-            );
-            draw_triangle(
-                vec2(x1, y1),
-                vec2(x1, chart_y + chart_h),
-                vec2(x2, chart_y + chart_h),
-                fill_color,
-            );
-            draw_line(x1, y1, x2, y2, 3.0, line_color);
+        match wire_type {
+            0 => {
+                let v = varint(buf, &mut pos)?;
+                if field == 3 {
+                    // zigzag
+                    time = Some(((v >> 1) as i64) ^ -((v & 1) as i64));
+                }
+            }
+            1 => pos += 8,
+            2 => {
+                let len = varint(buf, &mut pos)? as usize;
+                let bytes = buf.get(pos..pos.checked_add(len)?)?;
+                if field == 1 {
+                    symbol = Some(String::from_utf8_lossy(bytes).into_owned());
+                }
+                pos += len;
+            }
+            5 => {
+                let bytes: [u8; 4] = buf.get(pos..pos + 4)?.try_into().ok()?;
+                let v = f32::from_le_bytes(bytes);
+                match field {
+                    2 => price = Some(v),
+                    8 => change_percent = Some(v),
+                    _ => {}
+                }
+                pos += 4;
+            }
+            _ => return None,
         }
+    }
 
-        // etichette destra
-        for i in 0..5 {
-            let frac = i as f32 / 4.0;
-            let price = max_val - frac * val_range;
-            let gy = chart_y + frac * chart_h;
+    Some(StreamTick {
+        symbol: symbol?,
+        price: price?,
+        change_percent,
+        time: time
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_else(Utc::now),
+    })
+}
 
-            let txt = format!("${:.2}", price);
-            let tw = 45.0;
-            draw_rectangle(
-                x + width - tw - 8.0,
-                gy - 8.0,
-                tw,
-                14.0,
-                Color::from_rgba(30, 30, 40, 200),
+// Simboli aggiunti o tolti dalla lista mentre lo stream è attivo
+#[derive(Debug)]
+enum StreamCommand {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+// Aggiorna l'elenco dei simboli seguiti e ritorna i messaggi da inviare
+// al server; a ogni riconnessione si sottoscrive di nuovo l'elenco intero
+fn apply_stream_commands(
+    commands: &Receiver<StreamCommand>,
+    symbols: &mut Vec<String>,
+) -> Vec<String> {
+    commands
+        .try_iter()
+        .map(|command| match command {
+            StreamCommand::Subscribe(symbol) => {
+                if !symbols.contains(&symbol) {
+                    symbols.push(symbol.clone());
+                }
+                serde_json::json!({ "subscribe": [symbol] }).to_string()
+            }
+            StreamCommand::Unsubscribe(symbol) => {
+                symbols.retain(|s| *s != symbol);
+                serde_json::json!({ "unsubscribe": [symbol] }).to_string()
+            }
+        })
+        .collect()
+}
+
+// Resta connesso allo stream e riconnette con backoff quando cade.
+// `connected` è letto dalla UI per mostrare lo stato "live".
+#[allow(clippy::too_many_arguments)]
+fn start_stream_worker(
+    supervisor: &mut Supervisor,
+    updates: UpdateSender,
+    url: String,
+    symbols: Vec<String>,
+    commands: Receiver<StreamCommand>,
+    connected: Arc<AtomicBool>,
+    store: Option<Arc<TickStore>>,
+    activity: Arc<StreamActivity>,
+) {
+    // Condivisi tra un riavvio e l'altro del worker
+    let symbols = Mutex::new(symbols);
+    let commands = Mutex::new(commands);
+
+    supervisor.spawn("stream", move |ctl| {
+        let mut symbols = symbols.lock().unwrap_or_else(|e| e.into_inner());
+        let commands = commands.lock().unwrap_or_else(|e| e.into_inner());
+        let mut backoff = Duration::from_secs(1);
+        let mut last_stored = HashMap::new();
+
+        while !ctl.is_cancelled() {
+            let result = run_stream(
+                &updates,
+                &url,
+                &mut symbols,
+                &commands,
+                &connected,
+                store.as_deref(),
+                &activity,
+                &mut last_stored,
+                ctl,
             );
-            draw_text(&txt, x + width - tw - 5.0, gy + 4.0, 13.0, line_color);
+
+            // La connessione era stata stabilita: riparti dal backoff minimo
+            if connected.swap(false, Ordering::Relaxed) {
+                backoff = Duration::from_secs(1);
+            }
+            if let Err(err) = result {
+                eprintln!("Stream {}: {}", url, err);
+            }
+
+            ctl.sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_secs(60));
         }
+    });
+}
 
-        // Legenda
-        let ly = y + height - 10.0;
-        draw_rectangle(
-            x + 8.0,
-            ly - 22.0,
-            width - 16.0,
-            24.0,
-            Color::from_rgba(20, 20, 25, 220),
-        );
-        draw_rectangle_lines(
-            x + 8.0,
-            ly - 22.0,
-            width - 16.0,
-            24.0,
-            1.0,
-            Color::from_rgba(50, 50, 60, 255),
-        );
+// L'archivio riceve al massimo un tick ogni STREAM_SAMPLE_SECS per simbolo,
+// come la serie live: `last_stored` ricorda l'ultimo scritto
+#[allow(clippy::too_many_arguments)]
+fn run_stream(
+    updates: &UpdateSender,
+    url: &str,
+    symbols: &mut Vec<String>,
+    commands: &Receiver<StreamCommand>,
+    connected: &AtomicBool,
+    store: Option<&TickStore>,
+    activity: &StreamActivity,
+    last_stored: &mut HashMap<String, DateTime<Utc>>,
+    ctl: &WorkerControl,
+) -> FetchResult<()> {
+    let (mut socket, _) = tungstenite::connect(url)?;
 
-        draw_line(x + 12.0, ly - 10.0, x + 30.0, ly - 10.0, 3.0, line_color);
-        draw_text(
-            &format!("Ora (${:.2} – {:.2})", stock.current_min, stock.current_max),
-            x + 35.0,
-            ly - 5.0,
-            15.0,
-            WHITE,
-        );
+    // Letture brevi per accorgersi presto della chiusura; senza messaggi
+    // per STREAM_IDLE_TIMEOUT la connessione è considerata morta
+    let stream = match socket.get_ref() {
+        MaybeTlsStream::Plain(s) => Some(s),
+        MaybeTlsStream::NativeTls(s) => Some(s.get_ref()),
+        _ => None,
+    };
+    if let Some(stream) = stream {
+        stream
+            .set_read_timeout(Some(CONTROL_TICK))
+            .map_err(|err| FetchError::Network(err.to_string()))?;
+    }
 
-        let midx = x + width / 2.0 + 10.0;
-        draw_line(
-            midx,
-            ly - 10.0,
-            midx + 18.0,
-            ly - 10.0,
-            2.2,
-            Color::from_rgba(100, 150, 255, 255),
-        );
+    // Le modifiche arrivate mentre si era disconnessi entrano nell'elenco iniziale
+    apply_stream_commands(commands, symbols);
+    let subscribe = serde_json::json!({ "subscribe": symbols }).to_string();
+    socket.send(Message::Text(subscribe))?;
+    connected.store(true, Ordering::Relaxed);
+    let mut last_message = Instant::now();
 
-        if stock.prices_24h_ago.len() >= 2 {
-            draw_text(
-                &format!("24h (${:.2} – {:.2})", stock.h24_min, stock.h24_max),
-                midx + 23.0,
-                ly - 5.0,
-                15.0,
-                Color::from_rgba(150, 170, 200, 255),
-            );
-        } else {
-            draw_text(
-                "24h",
-                midx + 23.0,
-                ly - 5.0,
-                15.0,
-                Color::from_rgba(150, 170, 200, 255),
-            );
+    while !ctl.is_cancelled() {
+        for message in apply_stream_commands(commands, symbols) {
+            socket.send(Message::Text(message))?;
+        }
+
+        let msg = match socket.read() {
+            Ok(msg) => msg,
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(err) => match FetchError::from(err) {
+                FetchError::Timeout if last_message.elapsed() < STREAM_IDLE_TIMEOUT => continue,
+                err => return Err(err),
+            },
+        };
+        last_message = Instant::now();
+
+        let Some(tick) = parse_stream_message(&msg) else {
+            continue;
+        };
+
+        if let Ok(mut seen) = activity.lock() {
+            seen.insert(tick.symbol.clone(), Utc::now());
+        }
+        if let Some(store) = store {
+            let due = last_stored
+                .get(&tick.symbol)
+                .is_none_or(|t| (tick.time - *t).num_seconds() >= STREAM_SAMPLE_SECS);
+            if due {
+                store.append(&tick.symbol, tick.time, tick.price);
+                last_stored.insert(tick.symbol.clone(), tick.time);
+            }
+        }
+        if updates.send(StockUpdate::Tick(tick)).is_err() {
+            return Ok(());
         }
     }
+
+    let _ = socket.close(None);
+    Ok(())
 }
 
 // ────────────────────────────────────────────────
-// Pannello grafici – con limite altezza
+// Archivio tick su disco (log append-only, un file per simbolo)
 // ────────────────────────────────────────────────
 
-fn draw_charts_panel(
-    stocks: &HashMap<String, StockData>,
-    selected_symbols: &HashSet<String>,
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
-) {
-    draw_rectangle(x, y, width, height, Color::from_rgba(20, 20, 30, 255));
+// Ogni riga è "timestamp_ms,prezzo". All'avvio e poi ogni
+// TICK_COMPACT_INTERVAL le righe più vecchie della retention vengono scartate
+// e il file riscritto, così non cresce all'infinito anche senza riavvii.
+const TICK_COMPACT_INTERVAL: Duration = Duration::from_secs(3600);
 
-    if selected_symbols.is_empty() {
-        draw_text(
-            "Seleziona uno o più titoli dalla lista a sinistra",
-            x + width / 2.0 - 200.0,
-            y + height / 2.0,
-            20.0,
-            GRAY,
-        );
-        return;
+struct TickStore {
+    dir: PathBuf,
+    retention: chrono::Duration,
+    files: Mutex<HashMap<String, fs::File>>,
+    // Ultimo tick scritto per simbolo: tick non più recenti (mercato fermo) si scartano
+    last_times: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl TickStore {
+    fn open(dir: &str, retention: chrono::Duration) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            retention,
+            files: Mutex::new(HashMap::new()),
+            last_times: Mutex::new(HashMap::new()),
+        })
     }
 
-    let selected: Vec<&String> = selected_symbols.iter().collect();
-    let count = selected.len();
+    fn path(&self, symbol: &str) -> PathBuf {
+        // ^GSPC, EURUSD=X, … → nomi di file validi ovunque
+        let name: String = symbol
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir.join(format!("{}.log", name))
+    }
 
-    let cols = if count == 1 {
-        1
-    } else if count <= 4 {
-        2
-    } else {
-        3
-    };
-    let rows = (count + cols - 1) / cols;
+    fn append(&self, symbol: &str, time: DateTime<Utc>, price: f32) {
+        if let Ok(mut last_times) = self.last_times.lock() {
+            match last_times.entry(symbol.to_string()) {
+                Entry::Occupied(e) if *e.get() >= time => return,
+                Entry::Occupied(mut e) => {
+                    e.insert(time);
+                }
+                Entry::Vacant(e) => {
+                    e.insert(time);
+                }
+            }
+        }
 
-    let padding = 15.0;
-    let max_chart_h = 500.0;
+        let Ok(mut files) = self.files.lock() else {
+            return;
+        };
 
-    let avail_w = (width - padding * (cols as f32 + 1.0)) / cols as f32;
-    let avail_h = (height - padding * (rows as f32 + 1.0)) / rows as f32;
+        let file = match files.entry(symbol.to_string()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                match fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(symbol))
+                {
+                    Ok(file) => e.insert(file),
+                    Err(err) => {
+                        eprintln!("Tick store {}: {}", symbol, err);
+                        return;
+                    }
+                }
+            }
+        };
 
-    let chart_h = avail_h.min(max_chart_h);
-    let chart_w = avail_w;
+        if let Err(err) = writeln!(file, "{},{}", time.timestamp_millis(), price) {
+            eprintln!("Tick store {}: {}", symbol, err);
+        }
+    }
 
-    let total_charts_h = rows as f32 * chart_h + padding * (rows as f32 + 1.0);
-    let v_offset = if total_charts_h < height {
-        (height - total_charts_h) / 2.0
-    } else {
-        0.0
+    // Tick ancora dentro la retention, in ordine cronologico
+    fn load(&self, symbol: &str) -> Vec<(DateTime<Utc>, f32)> {
+        let ticks = self.trim_file(&self.path(symbol));
+        if let (Some((last, _)), Ok(mut last_times)) = (ticks.last(), self.last_times.lock()) {
+            last_times.insert(symbol.to_string(), *last);
+        }
+        ticks
+    }
+
+    // Compattazione periodica di tutti i file, anche dei simboli non più in lista.
+    // Il lock sui file blocca gli append durante la riscrittura; gli handle
+    // aperti vengono chiusi e riaperti al prossimo tick.
+    fn compact(&self) {
+        let Ok(mut files) = self.files.lock() else {
+            return;
+        };
+        files.clear();
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("Tick store {}: {}", self.dir.display(), err);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                self.trim_file(&path);
+            }
+        }
+    }
+
+    // Legge un file scartando le righe fuori retention; lo riscrive solo se
+    // qualcosa è stato scartato
+    fn trim_file(&self, path: &Path) -> Vec<(DateTime<Utc>, f32)> {
+        let Ok(body) = fs::read_to_string(path) else {
+            return Vec::new();
+        };
+
+        let cutoff = Utc::now() - self.retention;
+        let mut total = 0;
+        let mut ticks: Vec<(DateTime<Utc>, f32)> = body
+            .lines()
+            .inspect(|_| total += 1)
+            .filter_map(|line| {
+                let (ts, price) = line.split_once(',')?;
+                let time = DateTime::from_timestamp_millis(ts.trim().parse().ok()?)?;
+                Some((time, price.trim().parse().ok()?))
+            })
+            .filter(|(time, _)| *time >= cutoff)
+            .collect();
+        ticks.sort_by_key(|(time, _)| *time);
+
+        if ticks.len() < total {
+            let compacted: String = ticks
+                .iter()
+                .map(|(time, price)| format!("{},{}\n", time.timestamp_millis(), price))
+                .collect();
+            if let Err(err) = fs::write(path, compacted) {
+                eprintln!("Tick store {}: {}", path.display(), err);
+            }
+        }
+
+        ticks
+    }
+}
+
+fn start_tick_compactor(supervisor: &mut Supervisor, store: Arc<TickStore>) {
+    supervisor.spawn("tick-compact", move |ctl| {
+        while ctl.sleep(TICK_COMPACT_INTERVAL) {
+            store.compact();
+        }
+    });
+}
+
+// ────────────────────────────────────────────────
+// Snapshot per l'avvio immediato (warm start)
+// ────────────────────────────────────────────────
+
+// Ultimo stato noto di ogni titolo, salvato all'uscita e periodicamente:
+// all'avvio la lista lo mostra subito invece di "Caricamento..."
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    saved_at: DateTime<Utc>,
+    stocks: Vec<SnapshotEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry {
+    symbol: String,
+    range: ChartRange,
+    current_price: f32,
+    change_percent: f32,
+    change_range_percent: f32,
+    prices: Vec<f32>,
+    timestamps: Vec<DateTime<Utc>>,
+    history: Vec<Candle>,
+    #[serde(default)]
+    session: Option<TradingSession>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    price_hint: Option<u32>,
+}
+
+impl SnapshotEntry {
+    fn from_stock(stock: &StockData) -> Self {
+        Self {
+            symbol: stock.symbol.clone(),
+            range: stock.range,
+            current_price: stock.current_price,
+            change_percent: stock.change_percent,
+            change_range_percent: stock.change_range_percent,
+            prices: stock.live.prices.iter().copied().collect(),
+            timestamps: stock.live.times.iter().copied().collect(),
+            history: stock.history.clone(),
+            session: stock.session.clone(),
+            currency: stock.currency.clone(),
+            price_hint: stock.price_hint,
+        }
+    }
+}
+
+fn load_snapshot(path: &str) -> FetchResult<Snapshot> {
+    let body = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+// Scrive su un file temporaneo e poi rinomina: un'uscita a metà
+// scrittura non lascia uno snapshot troncato.
+fn save_snapshot(path: &str, entries: Vec<SnapshotEntry>) -> FetchResult<()> {
+    let snapshot = Snapshot {
+        saved_at: Utc::now(),
+        stocks: entries,
     };
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_string(&snapshot)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
 
-    for (i, &symbol) in selected.iter().enumerate() {
-        if let Some(stock) = stocks.get(symbol) {
-            let col = i % cols;
-            let row = i / cols;
+fn snapshot_entries(stocks: &HashMap<String, StockData>) -> Vec<SnapshotEntry> {
+    stocks
+        .values()
+        .filter(|s| s.current_price > 0.0 || !s.history.is_empty())
+        .map(SnapshotEntry::from_stock)
+        .collect()
+}
+
+// Il render loop copia i dati ogni `snapshot_interval` e li passa qui:
+// serializzazione e scrittura su disco restano fuori dal frame
+fn start_snapshot_worker(supervisor: &mut Supervisor, path: String) -> Sender<Vec<SnapshotEntry>> {
+    let (tx, rx) = mpsc::channel::<Vec<SnapshotEntry>>();
+    let rx = Mutex::new(rx);
+    supervisor.spawn("snapshot", move |ctl| {
+        let rx = rx.lock().unwrap_or_else(|e| e.into_inner());
+        while !ctl.is_cancelled() {
+            match rx.recv_timeout(CONTROL_TICK) {
+                Ok(entries) => {
+                    if let Err(err) = save_snapshot(&path, entries) {
+                        eprintln!("Snapshot {}: {}", path, err);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+    tx
+}
+
+// ────────────────────────────────────────────────
+// Watchlist e ricerca dei simboli
+// ────────────────────────────────────────────────
+
+// Lista iniziale quando non esiste ancora un file di watchlist
+const DEFAULT_WATCHLIST: [&str; 27] = [
+    "SPY", "QQQ", "DIA", "IWM", "AAPL", "MSFT", "GOOGL", "AMZN", "TSLA", "NVDA", "META", "NFLX",
+    "AMD", "INTC", "JPM", "BAC", "WMT", "V", "MA", "XOM", "BTC-USD", "ETH-USD", "BNB-USD",
+    "XRP-USD", "ADA-USD", "SOL-USD", "DOGE-USD",
+];
+
+const SEARCH_MAX_RESULTS: usize = 8;
+// Attesa dopo l'ultimo tasto prima di interrogare il provider
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Un risultato della ricerca: nome, borsa e tipo servono solo da etichetta
+#[derive(Debug, Clone)]
+struct SymbolMatch {
+    symbol: String,
+    name: Option<String>,
+    exchange: Option<String>,
+    kind: Option<String>,
+}
+
+impl SymbolMatch {
+    fn from_yahoo(quote: SearchQuote) -> Option<Self> {
+        Some(Self {
+            symbol: quote.symbol?,
+            name: quote.longname.or(quote.shortname),
+            exchange: quote.exch_disp.or(quote.exchange),
+            kind: quote.type_disp.or(quote.quote_type),
+        })
+    }
+}
+
+// Simboli mostrati nella lista, nell'ordine di inserimento. Ogni aggiunta
+// o rimozione riscrive il file, così la lista sopravvive al riavvio.
+#[derive(Debug)]
+struct Watchlist {
+    symbols: Vec<String>,
+    // Stessi simboli di `symbols`, per controlli in O(1) anche con migliaia di titoli
+    members: HashSet<String>,
+    path: Option<String>,
+    // Generati per lo stress test del simulatore: in lista ma mai salvati
+    generated: HashSet<String>,
+}
+
+impl Watchlist {
+    // Un simbolo per riga; righe vuote e commenti (#) ignorati
+    fn load(path: Option<&str>) -> Self {
+        let saved = path.and_then(|path| match fs::read_to_string(path) {
+            Ok(body) => Some(
+                body.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_uppercase)
+                    .collect::<Vec<_>>(),
+            ),
+            // Primo avvio: si parte dalla lista predefinita
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                eprintln!("Watchlist {} ignorata: {}", path, err);
+                None
+            }
+        });
+
+        let mut watchlist = Self {
+            symbols: Vec::new(),
+            members: HashSet::new(),
+            path: path.map(String::from),
+            generated: HashSet::new(),
+        };
+        let symbols =
+            saved.unwrap_or_else(|| DEFAULT_WATCHLIST.iter().map(|s| s.to_string()).collect());
+        for symbol in &symbols {
+            watchlist.add(symbol);
+        }
+        watchlist
+    }
+
+    fn contains(&self, symbol: &str) -> bool {
+        self.members.contains(symbol)
+    }
+
+    // false se il simbolo era già in lista
+    fn add(&mut self, symbol: &str) -> bool {
+        if !self.members.insert(symbol.to_string()) {
+            return false;
+        }
+        self.symbols.push(symbol.to_string());
+        true
+    }
+
+    fn add_generated(&mut self, symbol: String) {
+        if self.add(&symbol) {
+            self.generated.insert(symbol);
+        }
+    }
 
-            let cx = x + padding + col as f32 * (chart_w + padding);
-            let cy = y + padding + v_offset + row as f32 * (chart_h + padding);
+    fn remove(&mut self, symbol: &str) -> bool {
+        if !self.members.remove(symbol) {
+            return false;
+        }
+        self.symbols.retain(|s| s != symbol);
+        self.generated.remove(symbol);
+        true
+    }
 
-            draw_mini_chart(stock, cx, cy, chart_w, chart_h);
+    // Come lo snapshot: file temporaneo e rename
+    fn save(&self) -> FetchResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut body = String::from("# Un simbolo per riga, nell'ordine della lista\n");
+        for symbol in self.symbols.iter().filter(|s| !self.generated.contains(*s)) {
+            body.push_str(symbol);
+            body.push('\n');
         }
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, body)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
     }
 }
 
-// ────────────────────────────────────────────────
-// Worker di aggiornamento (invariati)
-// ────────────────────────────────────────────────
+// Elenco locale per la ricerca offline, una riga per strumento:
+// SIMBOLO,Nome,Borsa,Tipo (i campi dopo il simbolo sono facoltativi)
+#[derive(Debug)]
+struct SymbolDirectory {
+    entries: Vec<SymbolMatch>,
+}
 
-fn start_update_worker(
-    stocks: Arc<Mutex<HashMap<String, StockData>>>,
-    last_update: Arc<Mutex<DateTime<Utc>>>,
-    symbols: Vec<String>,
-) {
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(60));
-            let now = Utc::now();
-
-            for symbol in &symbols {
-                if let Ok((price, change)) = fetch_stock_data(symbol) {
-                    if let Ok(mut lock) = stocks.lock() {
-                        if let Some(s) = lock.get_mut(symbol) {
-                            s.prices.push(price);
-                            s.timestamps.push(now);
-                            if s.prices.len() > 60 {
-                                s.prices.remove(0);
-                                s.timestamps.remove(0);
-                            }
-                            s.current_price = price;
-                            s.change_percent = change;
-
-                            // aggiorna min/max
-                            if !s.prices.is_empty() {
-                                s.current_min =
-                                    s.prices.iter().cloned().fold(f32::INFINITY, f32::min);
-                                s.current_max =
-                                    s.prices.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
-                            }
-                        }
-                    }
+impl SymbolDirectory {
+    fn load(path: &str) -> FetchResult<Self> {
+        let body = fs::read_to_string(path)?;
+        let entries = body
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+                let mut fields = line.split(',').map(str::trim);
+                let symbol = fields.next()?.to_uppercase();
+                // Riga di intestazione
+                if symbol == "SYMBOL" || symbol == "SIMBOLO" {
+                    return None;
                 }
+                let mut field = || fields.next().filter(|f| !f.is_empty()).map(String::from);
+                Some(SymbolMatch {
+                    symbol,
+                    name: field(),
+                    exchange: field(),
+                    kind: field(),
+                })
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    // Prima il ticker esatto, poi i ticker che iniziano con la ricerca,
+    // poi quelli che la contengono nel ticker o nel nome
+    fn search(&self, query: &str) -> Vec<SymbolMatch> {
+        let upper = query.to_uppercase();
+        let lower = query.to_lowercase();
+        let mut ranked: Vec<(u8, &SymbolMatch)> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let rank = if entry.symbol == upper {
+                    0
+                } else if entry.symbol.starts_with(&upper) {
+                    1
+                } else if entry.symbol.contains(&upper)
+                    || entry
+                        .name
+                        .as_ref()
+                        .is_some_and(|name| name.to_lowercase().contains(&lower))
+                {
+                    2
+                } else {
+                    return None;
+                };
+                Some((rank, entry))
+            })
+            .collect();
+        ranked.sort_by_key(|(rank, entry)| (*rank, entry.symbol.len()));
+        ranked
+            .into_iter()
+            .take(SEARCH_MAX_RESULTS)
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+}
+
+// Le ricerche partono anche in pausa, come le ricariche dello storico:
+// le chiede l'utente. Mentre si digita conta solo l'ultimo testo.
+fn start_search_worker(
+    supervisor: &mut Supervisor,
+    updates: UpdateSender,
+    provider: Arc<dyn MarketDataProvider>,
+    directory: Option<SymbolDirectory>,
+) -> Sender<String> {
+    let (tx, rx) = mpsc::channel::<String>();
+    let rx = Mutex::new(rx);
+    supervisor.spawn("search", move |ctl| {
+        let rx = rx.lock().unwrap_or_else(|e| e.into_inner());
+        while !ctl.is_cancelled() {
+            let mut query = match rx.recv_timeout(CONTROL_TICK) {
+                Ok(query) => query,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            while let Ok(newer) = rx.recv_timeout(SEARCH_DEBOUNCE) {
+                query = newer;
             }
 
-            if let Ok(mut lu) = last_update.lock() {
-                *lu = now;
+            let result = match &directory {
+                Some(directory) => Ok(directory.search(&query)),
+                None => provider.search_symbols(&query),
+            };
+            if updates.send(StockUpdate::Search { query, result }).is_err() {
+                break;
             }
         }
     });
+    tx
 }
 
-fn start_24h_update_worker(stocks: Arc<Mutex<HashMap<String, StockData>>>, symbols: Vec<String>) {
-    thread::spawn(move || {
-        loop {
-            for symbol in &symbols {
-                if let Ok((prices, timestamps)) = fetch_24h_historical_data(symbol) {
-                    if let Ok(mut lock) = stocks.lock() {
-                        if let Some(s) = lock.get_mut(symbol) {
-                            s.prices_24h_ago = prices.clone();
-                            s.timestamps_24h_ago = timestamps;
-
-                            if !prices.is_empty() {
-                                s.h24_min = prices.iter().cloned().fold(f32::INFINITY, f32::min);
-                                s.h24_max =
-                                    prices.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
-
-                                if s.current_price > 0.0 && !prices.is_empty() {
-                                    let p24 = prices[0];
-                                    if p24 > 0.0 {
-                                        s.change_24h_percent =
-                                            ((s.current_price - p24) / p24) * 100.0;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            thread::sleep(Duration::from_secs(300));
+#[macroquad::main("Stock Tracker – Ottimizzato")]
+async fn main() {
+    let config = Config::load();
+    let (updates_tx, updates_rx) = mpsc::channel::<StockUpdate>();
+    let mut app = App::new(updates_rx);
+    let mut scroll_offset = 0.0f32;
+
+    let mut watchlist = Watchlist::load(config.watchlist_path.as_deref());
+
+    // Stress test della UI con il simulatore: SIM00001, SIM00002, …
+    // Solo se "sim" è nella catena, altrimenti gli altri provider li
+    // cercherebbero davvero e fallirebbero uno per uno.
+    if config.providers.iter().any(|p| p == "sim") {
+        for i in 1..=config.sim_symbols {
+            watchlist.add_generated(format!("SIM{:05}", i));
         }
+    }
+
+    let store = config.tick_store_dir.as_deref().and_then(|dir| {
+        TickStore::open(dir, config.tick_retention)
+            .map_err(|err| eprintln!("Tick store disattivato ({}): {}", dir, err))
+            .ok()
+            .map(Arc::new)
     });
-}
 
-fn initial_fetch(stocks: Arc<Mutex<HashMap<String, StockData>>>, symbols: Vec<String>) {
-    let stocks_c = stocks.clone();
-    let value = symbols.clone();
-    thread::spawn(move || {
-        for sym in &value {
-            if let Ok((price, ch)) = fetch_stock_data(sym) {
-                if let Ok(mut lock) = stocks_c.lock() {
-                    if let Some(s) = lock.get_mut(sym) {
-                        s.current_price = price;
-                        s.change_percent = ch;
-                        s.prices.push(price);
-                        s.timestamps.push(Utc::now());
-
-                        if !s.prices.is_empty() {
-                            s.current_min = s.prices.iter().cloned().fold(f32::INFINITY, f32::min);
-                            s.current_max =
-                                s.prices.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    // Storia dei tick dalle sessioni precedenti, prima di toccare la rete
+    let new_stock = |sym: &str| {
+        let mut data = StockData::new(sym, config.live_capacity);
+        if let Some(store) = &store {
+            data.load_ticks(&store.load(sym));
+        }
+        data
+    };
+
+    {
+        let stocks = &mut app.stocks;
+        for sym in &watchlist.symbols {
+            stocks.insert(sym.clone(), new_stock(sym));
+        }
+
+        if let Some(path) = &config.snapshot_path {
+            match load_snapshot(path) {
+                Ok(snapshot) => {
+                    for entry in snapshot.stocks {
+                        if let Some(s) = stocks.get_mut(&entry.symbol) {
+                            s.restore(entry);
                         }
                     }
                 }
+                // Primo avvio: nessuno snapshot ancora
+                Err(FetchError::Io(_)) if !std::path::Path::new(path).exists() => {}
+                Err(err) => eprintln!("Snapshot {} ignorato: {}", path, err),
             }
         }
-    });
+    }
+    let restored: Vec<String> = app
+        .stocks
+        .values()
+        .filter_map(|s| s.currency.clone())
+        .collect();
+    for currency in restored {
+        app.note_currency(&currency);
+    }
 
-    let stocks_c2 = stocks.clone();
-    thread::spawn(move || {
-        thread::sleep(Duration::from_secs(3));
-        for sym in &symbols {
-            if let Ok((prices, ts)) = fetch_24h_historical_data(sym) {
-                if let Ok(mut lock) = stocks_c2.lock() {
-                    if let Some(s) = lock.get_mut(sym) {
-                        s.prices_24h_ago = prices.clone();
-                        s.timestamps_24h_ago = ts;
-
-                        if !prices.is_empty() {
-                            s.h24_min = prices.iter().cloned().fold(f32::INFINITY, f32::min);
-                            s.h24_max = prices.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
-
-                            if s.current_price > 0.0 {
-                                let p24 = prices[0];
-                                if p24 > 0.0 {
-                                    s.change_24h_percent = ((s.current_price - p24) / p24) * 100.0;
-                                }
-                            }
-                        }
-                    }
-                }
+    let limiter = Arc::new(RateLimiter::new(config.rate_limit, config.rate_burst));
+    let retry = RetryPolicy {
+        max_retries: config.max_retries,
+        base_delay: Duration::from_millis(500),
+        max_delay: Duration::from_secs(20),
+    };
+    let tape = if let Some(path) = &config.replay_path {
+        match SessionReplay::load(path, config.replay_speed) {
+            Ok(replay) => Tape::Replay(Arc::new(replay)),
+            Err(err) => {
+                eprintln!("Replay {} non disponibile: {}", path, err);
+                Tape::Off
             }
         }
-    });
-}
+    } else if let Some(path) = &config.record_path {
+        match SessionRecorder::create(path) {
+            Ok(recorder) => Tape::Record(Arc::new(recorder)),
+            Err(err) => {
+                eprintln!("Registrazione {} disattivata: {}", path, err);
+                Tape::Off
+            }
+        }
+    } else {
+        Tape::Off
+    };
+    let provider = build_provider(&config, &limiter, &retry, &tape);
 
-#[macroquad::main("Stock Tracker – Ottimizzato")]
-async fn main() {
-    let mut app = App::new();
-    let mut scroll_offset = 0.0f32;
+    // Intervallo di partenza di ogni grafico (può venire dallo snapshot)
+    let ranges: Vec<(String, ChartRange)> = watchlist
+        .symbols
+        .iter()
+        .map(|sym| (sym.clone(), app.stocks[sym].range))
+        .collect();
 
-    let symbols = vec![
-        "SPY", "QQQ", "DIA", "IWM", "AAPL", "MSFT", "GOOGL", "AMZN", "TSLA", "NVDA", "META",
-        "NFLX", "AMD", "INTC", "JPM", "BAC", "WMT", "V", "MA", "XOM", "BTC-USD", "ETH-USD",
-        "BNB-USD", "XRP-USD", "ADA-USD", "SOL-USD", "DOGE-USD",
-    ]
-    .into_iter()
-    .map(String::from)
-    .collect::<Vec<_>>();
+    let calendar = Arc::new(MarketCalendar::load(config.holidays_path.as_deref()));
 
-    {
-        let mut stocks = app.stocks.lock().unwrap();
-        for sym in &symbols {
-            stocks.insert(
-                sym.clone(),
-                StockData {
-                    symbol: sym.clone(),
-                    prices: vec![],
-                    timestamps: vec![],
-                    prices_24h_ago: vec![],
-                    timestamps_24h_ago: vec![],
-                    current_price: 0.0,
-                    change_percent: 0.0,
-                    change_24h_percent: 0.0,
-                    current_min: f32::INFINITY,
-                    current_max: f32::NEG_INFINITY,
-                    h24_min: f32::INFINITY,
-                    h24_max: f32::NEG_INFINITY,
-                },
-            );
-        }
+    let mut supervisor = Supervisor::new();
+    let stream_activity = Arc::new(StreamActivity::default());
+    let (sched_tx, sched_rx) = mpsc::channel::<SchedulerCommand>();
+    start_scheduler(
+        &mut supervisor,
+        updates_tx.clone(),
+        provider.clone(),
+        Scheduler::new(
+            ranges,
+            config.intervals.clone(),
+            calendar.clone(),
+            tape.clone(),
+        ),
+        config.fetch_concurrency,
+        store.clone(),
+        stream_activity.clone(),
+        sched_rx,
+    );
+    let mut focus: (HashSet<String>, HashSet<String>) = Default::default();
+
+    let stream_connected = Arc::new(AtomicBool::new(false));
+    let stream_tx = config.stream_url.as_ref().map(|url| {
+        let (tx, rx) = mpsc::channel::<StreamCommand>();
+        start_stream_worker(
+            &mut supervisor,
+            updates_tx.clone(),
+            url.clone(),
+            watchlist.symbols.clone(),
+            rx,
+            stream_connected.clone(),
+            store.clone(),
+            stream_activity.clone(),
+        );
+        tx
+    });
+
+    let directory = config.symbols_file.as_deref().and_then(|path| {
+        SymbolDirectory::load(path)
+            .map_err(|err| eprintln!("Elenco simboli {} ignorato: {}", path, err))
+            .ok()
+    });
+    let search_tx = start_search_worker(&mut supervisor, updates_tx, provider.clone(), directory);
+
+    let snapshot_tx = config
+        .snapshot_path
+        .clone()
+        .map(|path| start_snapshot_worker(&mut supervisor, path));
+    if let Some(store) = &store {
+        start_tick_compactor(&mut supervisor, store.clone());
     }
+    let mut next_snapshot = Instant::now() + config.snapshot_interval;
 
-    initial_fetch(app.stocks.clone(), symbols.clone());
-    start_update_worker(app.stocks.clone(), app.last_update.clone(), symbols.clone());
-    start_24h_update_worker(app.stocks.clone(), symbols.clone());
+    // La chiusura della finestra passa dall'uscita del loop come Esc:
+    // altrimenti macroquad termina senza salvare lo snapshot finale
+    prevent_quit();
 
     loop {
         clear_background(Color::from_rgba(20, 20, 30, 255));
 
-        if is_key_pressed(KeyCode::Escape) {
+        if is_quit_requested() {
             break;
         }
 
+        // Mentre si scrive nella ricerca i tasti vanno alla casella
+        if !app.search.focused {
+            if is_key_pressed(KeyCode::Escape) {
+                break;
+            }
+            // P: sospende/riprende il polling (lo stream resta attivo)
+            if is_key_pressed(KeyCode::P) {
+                supervisor.control.toggle_pause();
+            }
+        }
+
+        supervisor.check();
+
+        app.apply_updates();
+
+        // Valuta mai vista: il suo cambio verso la valuta base entra nel polling
+        for currency in std::mem::take(&mut app.new_currencies) {
+            let Some(base) = &config.base_currency else {
+                continue;
+            };
+            let (major, _) = currency_unit(&currency);
+            let fx = fx_symbol(major, base);
+            if major != base && !app.stocks.contains_key(&fx) {
+                app.stocks
+                    .insert(fx.clone(), StockData::new(&fx, config.live_capacity));
+                let _ = sched_tx.send(SchedulerCommand::TrackQuote { symbol: fx });
+            }
+        }
+
+        if let Some(tx) = &snapshot_tx
+            && Instant::now() >= next_snapshot
+        {
+            let _ = tx.send(snapshot_entries(&app.stocks));
+            next_snapshot = Instant::now() + config.snapshot_interval;
+        }
+
         let screen_w = screen_width();
         let screen_h = screen_height();
         let list_w = 320.0;
         let charts_w = screen_w - list_w;
 
-        let stocks_guard = app.stocks.lock().unwrap();
-
-        let (clicked, new_scroll) = draw_list_panel(
-            &stocks_guard,
-            &symbols,
+        let search_x = 10.0;
+        let search_w = list_w - 20.0;
+        let (action, new_scroll, visible) = draw_list_panel(
+            &app.stocks,
+            config.base_currency.as_deref(),
+            &calendar,
+            tape.now(),
+            &watchlist.symbols,
             &app.selected_symbols,
             0.0,
             0.0,
@@ -917,17 +5656,54 @@ async fn main() {
             screen_h,
             scroll_offset,
             &mut app.scrollbar_state,
+            app.search.dropdown_rect(search_x, SEARCH_BOX_Y, search_w),
         );
 
         scroll_offset = new_scroll;
+        let visible: HashSet<String> = watchlist.symbols[visible].iter().cloned().collect();
 
-        if let Some(sym) = clicked {
-            if app.selected_symbols.contains(&sym) {
+        match action {
+            Some(ListAction::Toggle(sym)) => {
+                if app.selected_symbols.contains(&sym) {
+                    app.selected_symbols.remove(&sym);
+                } else if app.selected_symbols.len() < MAX_SELECTED {
+                    app.selected_symbols.insert(sym);
+                }
+                // else → potresti aggiungere un messaggio "Massimo raggiunto"
+            }
+            Some(ListAction::Remove(sym)) if watchlist.remove(&sym) => {
                 app.selected_symbols.remove(&sym);
-            } else if app.selected_symbols.len() < MAX_SELECTED {
-                app.selected_symbols.insert(sym);
+                let _ = sched_tx.send(SchedulerCommand::Remove {
+                    symbol: sym.clone(),
+                });
+                if let Some(tx) = &stream_tx {
+                    let _ = tx.send(StreamCommand::Unsubscribe(sym.clone()));
+                }
+                // Se è il cambio usato per la valuta base resta, fuori dalla lista
+                let fx_needed = config.base_currency.as_deref().is_some_and(|base| {
+                    app.currencies
+                        .iter()
+                        .any(|c| fx_symbol(currency_unit(c).0, base) == sym)
+                });
+                if fx_needed {
+                    let _ = sched_tx.send(SchedulerCommand::TrackQuote { symbol: sym });
+                } else {
+                    app.stocks.remove(&sym);
+                }
+                if let Err(err) = watchlist.save() {
+                    eprintln!("Watchlist: {}", err);
+                }
             }
-            // else → potresti aggiungere un messaggio "Massimo raggiunto"
+            Some(ListAction::Remove(_)) | None => {}
+        }
+
+        // Selezionati e visibili hanno la precedenza nello scheduler
+        if focus.0 != app.selected_symbols || focus.1 != visible {
+            focus = (app.selected_symbols.clone(), visible);
+            let _ = sched_tx.send(SchedulerCommand::Focus {
+                selected: focus.0.clone(),
+                visible: focus.1.clone(),
+            });
         }
 
         draw_line(
@@ -939,8 +5715,9 @@ async fn main() {
             Color::from_rgba(50, 50, 60, 255),
         );
 
-        draw_charts_panel(
-            &stocks_guard,
+        let range_change = draw_charts_panel(
+            &app.stocks,
+            config.base_currency.as_deref(),
             &app.selected_symbols,
             list_w,
             0.0,
@@ -948,17 +5725,730 @@ async fn main() {
             screen_h,
         );
 
-        let last_up = *app.last_update.lock().unwrap();
+        // Cambio intervallo: svuota lo storico e chiedi al worker di ricaricarlo
+        if let Some((sym, range)) = range_change
+            && let Some(s) = app.stocks.get_mut(&sym)
+        {
+            s.set_range(range);
+            let _ = sched_tx.send(SchedulerCommand::Reload { symbol: sym, range });
+        }
+
+        // Disegnata dopo la lista: il menu dei risultati le sta sopra
+        if let Some(sym) = draw_search_box(
+            &mut app.search,
+            &watchlist,
+            search_x,
+            SEARCH_BOX_Y,
+            search_w,
+        ) && watchlist.add(&sym)
+        {
+            let data = app
+                .stocks
+                .entry(sym.clone())
+                .or_insert_with(|| new_stock(&sym));
+            let _ = sched_tx.send(SchedulerCommand::Add {
+                symbol: sym.clone(),
+                range: data.range,
+            });
+            if let Some(tx) = &stream_tx {
+                let _ = tx.send(StreamCommand::Subscribe(sym.clone()));
+            }
+            if let Err(err) = watchlist.save() {
+                eprintln!("Watchlist: {}", err);
+            }
+            // Il nuovo simbolo è in fondo: scorri fin lì
+            scroll_offset = f32::MAX;
+        }
+        if let Some(query) = app.search.take_query() {
+            let _ = search_tx.send(query);
+        }
+
+        let last_up = app.last_update;
+        let mode = match &tape {
+            Tape::Replay(replay) => format!(
+                " - replay {}x {}{}",
+                config.replay_speed,
+                replay.clock().format("%Y-%m-%d %H:%M:%S"),
+                if replay.finished() { " (fine)" } else { "" }
+            ),
+            Tape::Record(_) => " - registrazione".to_string(),
+            Tape::Off if stream_connected.load(Ordering::Relaxed) => " - stream live".to_string(),
+            Tape::Off => String::new(),
+        };
+        let paused = if supervisor.control.is_paused() {
+            " - in pausa (P per riprendere)"
+        } else {
+            ""
+        };
         draw_text(
-            &format!("Aggiornamento: {}", last_up.format("%H:%M:%S")),
+            &format!(
+                "Aggiornamento: {} ({}){}{}",
+                last_up.format("%H:%M:%S"),
+                provider.name(),
+                mode,
+                paused
+            ),
             list_w + 15.0,
             screen_h - 10.0,
             14.0,
             GRAY,
         );
 
-        drop(stocks_guard); // esplicito, ma non strettamente necessario
-
         next_frame().await;
     }
+
+    // Esc o chiusura della finestra: i worker terminano la scrittura in corso
+    // (snapshot, compattazione dei tick) prima che il processo esca
+    drop(snapshot_tx);
+    supervisor.shutdown(SHUTDOWN_TIMEOUT);
+
+    if let Some(path) = &config.snapshot_path
+        && let Err(err) = save_snapshot(path, snapshot_entries(&app.stocks))
+    {
+        eprintln!("Snapshot {}: {}", path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stooq_symbol_maps_yahoo_conventions() {
+        let stooq = |symbol| stooq_symbol(symbol).unwrap();
+        assert_eq!(stooq("AAPL"), "aapl.us");
+        assert_eq!(stooq("BRK-B"), "brk-b.us");
+        assert_eq!(stooq("BTC-USD"), "btcusd");
+        assert_eq!(stooq("EURUSD=X"), "eurusd");
+        assert_eq!(stooq("VOD.L"), "vod.uk");
+        assert_eq!(stooq("SAP.DE"), "sap.de");
+        assert_eq!(stooq("7203.T"), "7203.jp");
+        assert_eq!(stooq("^GSPC"), "^spx");
+        assert_eq!(stooq("^IXIC"), "^ndq");
+        assert!(matches!(
+            stooq_symbol("^VIX"),
+            Err(FetchError::Provider { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_stooq_csv_reads_candles_with_and_without_volume() {
+        let body = "Date,Open,High,Low,Close,Volume\n\
+                    2025-03-03,10,12,9,11,1500\n\
+                    2025-03-04,11,13,10,12.5,2000\n";
+        let candles = parse_stooq_csv(body).unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].time.to_rfc3339(), "2025-03-03T00:00:00+00:00");
+        assert_eq!(candles[1].close, 12.5);
+        assert_eq!(candles[1].volume, 2000);
+
+        // FX e indici: niente colonna Volume
+        let candles =
+            parse_stooq_csv("Date,Open,High,Low,Close\n2025-03-03,1.08,1.09,1.07,1.085\n").unwrap();
+        assert_eq!(candles[0].volume, 0);
+    }
+
+    #[test]
+    fn parse_stooq_csv_skips_incomplete_rows() {
+        let body = "Date,Open,High,Low,Close\n\
+                    2025-03-03,N/D,N/D,N/D,N/D\n\
+                    2025-03-04,1,2,0.5,1.5\n";
+        let candles = parse_stooq_csv(body).unwrap();
+        assert_eq!(candles.len(), 1);
+        assert_eq!(candles[0].close, 1.5);
+
+        assert!(matches!(
+            parse_stooq_csv("Date,Open,High,Low,Close\n2025-03-03,N/D,N/D,N/D,N/D\n"),
+            Err(FetchError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_stooq_csv_reports_unknown_symbols() {
+        assert!(matches!(
+            parse_stooq_csv("No data"),
+            Err(FetchError::Provider { .. })
+        ));
+        assert!(matches!(parse_stooq_csv(""), Err(FetchError::Parse(_))));
+        assert!(matches!(
+            parse_stooq_csv("Date,Close\n"),
+            Err(FetchError::Parse(_))
+        ));
+    }
+
+    // PricingData come lo invia Yahoo, più un campo sconosciuto da saltare
+    fn pricing_data(symbol: &str, price: f32, time_ms: i64, change_percent: f32) -> Vec<u8> {
+        fn varint(mut v: u64, out: &mut Vec<u8>) {
+            while v >= 0x80 {
+                out.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            out.push(v as u8);
+        }
+
+        let mut buf = vec![0x0A, symbol.len() as u8];
+        buf.extend_from_slice(symbol.as_bytes());
+        buf.push(0x15);
+        buf.extend_from_slice(&price.to_le_bytes());
+        buf.push(0x18);
+        varint(((time_ms << 1) ^ (time_ms >> 63)) as u64, &mut buf);
+        buf.extend_from_slice(&[0x22, 3, b'U', b'S', b'D']);
+        buf.push(0x45);
+        buf.extend_from_slice(&change_percent.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn decode_pricing_data_reads_known_fields() {
+        let tick = decode_pricing_data(&pricing_data("BTC-USD", 64000.5, 1_700_000_000_000, -1.25))
+            .unwrap();
+        assert_eq!(tick.symbol, "BTC-USD");
+        assert_eq!(tick.price, 64000.5);
+        assert_eq!(tick.change_percent, Some(-1.25));
+        assert_eq!(tick.time.timestamp_millis(), 1_700_000_000_000);
+    }
+
+    #[test]
+    fn decode_pricing_data_rejects_truncated_input() {
+        let buf = pricing_data("AAPL", 190.0, 1_700_000_000_000, 0.5);
+        // Tagli a metà del simbolo, del prezzo e del varint del tempo
+        for len in [0, 3, 8, 12] {
+            assert!(decode_pricing_data(&buf[..len]).is_none(), "len {}", len);
+        }
+        // Lunghezza enorme: niente overflow né panic
+        assert!(
+            decode_pricing_data(&[
+                0x0A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01
+            ])
+            .is_none()
+        );
+        // Wire type non supportato
+        assert!(decode_pricing_data(&[0x0B]).is_none());
+    }
+
+    #[test]
+    fn parse_stream_message_accepts_envelope_base64_and_json() {
+        let encoded = BASE64.encode(pricing_data("ETH-USD", 3000.0, 1_700_000_000_000, 2.0));
+
+        let envelope = format!(r#"{{"type":"pricing","message":"{}"}}"#, encoded);
+        let tick = parse_stream_message(&Message::Text(envelope)).unwrap();
+        assert_eq!(tick.symbol, "ETH-USD");
+        assert_eq!(tick.price, 3000.0);
+
+        let tick = parse_stream_message(&Message::Text(encoded)).unwrap();
+        assert_eq!(tick.symbol, "ETH-USD");
+
+        let json = r#"{"symbol":"SPY","price":500.25,"changePercent":0.4,"time":1700000000000}"#;
+        let tick = parse_stream_message(&Message::Text(json.to_string())).unwrap();
+        assert_eq!(tick.symbol, "SPY");
+        assert_eq!(tick.price, 500.25);
+        assert_eq!(tick.change_percent, Some(0.4));
+        assert_eq!(tick.time.timestamp_millis(), 1_700_000_000_000);
+
+        assert!(parse_stream_message(&Message::Text("non è un tick".to_string())).is_none());
+        assert!(parse_stream_message(&Message::Ping(Vec::new())).is_none());
+    }
+
+    fn ring(capacity: usize, prices: &[f32]) -> PriceRing {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut ring = PriceRing::new(capacity);
+        for (i, &price) in prices.iter().enumerate() {
+            ring.push(start + chrono::Duration::seconds(i as i64), price);
+        }
+        ring
+    }
+
+    #[test]
+    fn price_ring_evicts_oldest_and_recomputes_extremes() {
+        let mut ring = ring(3, &[5.0, 1.0, 3.0]);
+        assert_eq!((ring.min, ring.max), (1.0, 5.0));
+
+        // Esce il massimo (5)
+        ring.push(
+            ring.last_time().unwrap() + chrono::Duration::seconds(1),
+            2.0,
+        );
+        assert_eq!(ring.len(), 3);
+        assert_eq!((ring.min, ring.max), (1.0, 3.0));
+
+        // Esce il minimo (1)
+        ring.push(
+            ring.last_time().unwrap() + chrono::Duration::seconds(1),
+            2.5,
+        );
+        assert_eq!((ring.min, ring.max), (2.0, 3.0));
+
+        // Esce un valore intermedio: gli estremi restano
+        ring.push(
+            ring.last_time().unwrap() + chrono::Duration::seconds(1),
+            10.0,
+        );
+        assert_eq!(ring.prices, [2.0, 2.5, 10.0]);
+        assert_eq!((ring.min, ring.max), (2.0, 10.0));
+    }
+
+    #[test]
+    fn price_ring_grows_on_demand() {
+        let ring = ring(10_000, &[1.0, 2.0]);
+        assert_eq!(ring.len(), 2);
+        assert!(ring.prices.capacity() < 10_000);
+    }
+
+    #[test]
+    fn price_ring_set_last_updates_extremes() {
+        let mut ring = ring(4, &[2.0, 4.0, 6.0]);
+
+        // L'ultimo punto era il massimo e scende
+        ring.set_last(3.0);
+        assert_eq!((ring.min, ring.max), (2.0, 4.0));
+
+        // Nuovo minimo
+        ring.set_last(1.0);
+        assert_eq!((ring.min, ring.max), (1.0, 4.0));
+
+        // Era il minimo e risale
+        ring.set_last(5.0);
+        assert_eq!((ring.min, ring.max), (2.0, 5.0));
+        assert_eq!(ring.len(), 3);
+
+        let mut empty = PriceRing::new(4);
+        empty.set_last(1.0);
+        assert!(empty.is_empty());
+    }
+
+    fn scheduler(symbols: &[&str]) -> Scheduler {
+        let intervals = PollIntervals {
+            crypto: Duration::from_secs(5),
+            fx: Duration::from_secs(10),
+            index: Duration::from_secs(10),
+            equity: Duration::from_secs(10),
+            history: Duration::from_secs(60),
+            offscreen_factor: 3.0,
+        };
+        let ranges = symbols
+            .iter()
+            .map(|s| (s.to_string(), ChartRange::default()))
+            .collect();
+        Scheduler::new(
+            ranges,
+            intervals,
+            Arc::new(MarketCalendar::load(None)),
+            Tape::Off,
+        )
+    }
+
+    fn due_jobs(scheduler: &mut Scheduler, now: Instant) -> Vec<(String, JobKind)> {
+        let mut jobs: Vec<_> = scheduler
+            .take_due(now, |_| usize::MAX)
+            .into_iter()
+            .map(|job| (job.symbol, job.kind))
+            .collect();
+        jobs.sort();
+        jobs
+    }
+
+    #[test]
+    fn scheduler_take_due_runs_each_job_once_per_interval() {
+        let mut sched = scheduler(&["AAPL", "BTC-USD"]);
+        let start = Instant::now() + Duration::from_millis(10);
+
+        // Fetch iniziale: tutto subito, una volta sola
+        assert_eq!(due_jobs(&mut sched, start).len(), 4);
+        assert!(due_jobs(&mut sched, start).is_empty());
+
+        // Fuori schermo: crypto ogni 15 s, azioni ogni 30 s, storico ogni 180 s
+        assert_eq!(
+            due_jobs(&mut sched, start + Duration::from_secs(16)),
+            [("BTC-USD".to_string(), JobKind::Quote)]
+        );
+        assert_eq!(
+            due_jobs(&mut sched, start + Duration::from_secs(31)),
+            [
+                ("AAPL".to_string(), JobKind::Quote),
+                ("BTC-USD".to_string(), JobKind::Quote)
+            ]
+        );
+        assert!(
+            due_jobs(&mut sched, start + Duration::from_secs(181))
+                .contains(&("AAPL".to_string(), JobKind::History))
+        );
+    }
+
+    #[test]
+    fn scheduler_take_due_skips_removed_symbols() {
+        let mut sched = scheduler(&["AAPL", "MSFT"]);
+        sched.remove("MSFT");
+        let jobs = due_jobs(&mut sched, Instant::now() + Duration::from_millis(10));
+        assert!(jobs.iter().all(|(symbol, _)| symbol == "AAPL"));
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(
+            sched.next_due(|_| true),
+            Some(sched.jobs[&("AAPL".to_string(), JobKind::Quote)].due)
+        );
+    }
+
+    #[test]
+    fn scheduler_take_due_defers_jobs_over_the_limit() {
+        let mut sched = scheduler(&["AAPL", "MSFT", "SPY"]);
+        let start = Instant::now() + Duration::from_millis(10);
+
+        // Storico occupato: partono solo le quote, lo storico resta in scadenza
+        let jobs = sched.take_due(start, |kind| match kind {
+            JobKind::Quote => usize::MAX,
+            JobKind::History => 0,
+        });
+        assert!(jobs.iter().all(|job| job.kind == JobKind::Quote));
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(
+            sched.next_due(|kind| kind == JobKind::Quote),
+            Some(start + Duration::from_secs(30))
+        );
+        assert!(sched.next_due(|kind| kind == JobKind::History).unwrap() <= start);
+
+        // Poi a gruppi di due, senza perdere nessun simbolo
+        let mut histories = Vec::new();
+        for _ in 0..2 {
+            let jobs = sched.take_due(start, |kind| match kind {
+                JobKind::Quote => usize::MAX,
+                JobKind::History => 2,
+            });
+            assert!(jobs.len() <= 2);
+            histories.extend(jobs.into_iter().map(|job| job.symbol));
+        }
+        histories.sort();
+        assert_eq!(histories, ["AAPL", "MSFT", "SPY"]);
+        assert_eq!(
+            sched.next_due(|_| true),
+            Some(start + Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn scheduler_set_focus_brings_jobs_forward() {
+        let mut sched = scheduler(&["AAPL", "MSFT", "SPY"]);
+        let start = Instant::now() + Duration::from_millis(10);
+        due_jobs(&mut sched, start);
+
+        // Selezionato: metà intervallo (5 s); visibile: intervallo base (10 s)
+        sched.set_focus(
+            HashSet::from(["AAPL".to_string()]),
+            HashSet::from(["AAPL".to_string(), "MSFT".to_string()]),
+        );
+        assert_eq!(
+            due_jobs(&mut sched, start + Duration::from_secs(6)),
+            [("AAPL".to_string(), JobKind::Quote)]
+        );
+        assert_eq!(
+            due_jobs(&mut sched, start + Duration::from_secs(11)),
+            [
+                ("AAPL".to_string(), JobKind::Quote),
+                ("MSFT".to_string(), JobKind::Quote)
+            ]
+        );
+
+        // Fuori focus la scadenza già fissata resta, la successiva si allunga
+        sched.set_focus(HashSet::new(), HashSet::new());
+        assert_eq!(
+            due_jobs(&mut sched, start + Duration::from_secs(20)),
+            [("AAPL".to_string(), JobKind::Quote)]
+        );
+        assert_eq!(
+            due_jobs(&mut sched, start + Duration::from_secs(31)),
+            [
+                // Storico anticipato a 30 s quando AAPL era selezionato
+                ("AAPL".to_string(), JobKind::History),
+                ("MSFT".to_string(), JobKind::Quote),
+                ("SPY".to_string(), JobKind::Quote)
+            ]
+        );
+        assert!(due_jobs(&mut sched, start + Duration::from_secs(45)).is_empty());
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    // Orari NYSE di giovedì 2 luglio 2026 (EDT): il 3 è festivo (4 luglio di sabato)
+    fn nyse_session() -> TradingSession {
+        TradingSession {
+            pre: (utc("2026-07-02T08:00:00Z"), utc("2026-07-02T13:30:00Z")),
+            regular: (utc("2026-07-02T13:30:00Z"), utc("2026-07-02T20:00:00Z")),
+            post: (utc("2026-07-02T20:00:00Z"), utc("2026-07-03T00:00:00Z")),
+            timezone: US_TIMEZONE.to_string(),
+            gmtoffset: -4 * 3600,
+        }
+    }
+
+    #[test]
+    fn trading_session_status_within_a_day() {
+        let session = nyse_session();
+        let calendar = MarketCalendar::load(None);
+        let status = |now| session.status(utc(now), &calendar);
+
+        assert_eq!(
+            status("2026-07-02T06:00:00Z"),
+            (MarketStatus::Closed, utc("2026-07-02T08:00:00Z"))
+        );
+        assert_eq!(
+            status("2026-07-02T10:00:00Z"),
+            (MarketStatus::Pre, utc("2026-07-02T13:30:00Z"))
+        );
+        assert_eq!(
+            status("2026-07-02T14:00:00Z"),
+            (MarketStatus::Open, utc("2026-07-02T20:00:00Z"))
+        );
+        assert_eq!(
+            status("2026-07-02T21:00:00Z"),
+            (MarketStatus::Post, utc("2026-07-03T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn trading_session_status_skips_holidays_and_weekends() {
+        let session = nyse_session();
+        let calendar = MarketCalendar::load(None);
+        let monday_pre = utc("2026-07-06T08:00:00Z");
+
+        // Festivo di venerdì e poi il weekend: si riapre lunedì
+        for now in [
+            "2026-07-03T00:30:00Z",
+            "2026-07-03T14:00:00Z",
+            "2026-07-04T14:00:00Z",
+            "2026-07-05T23:00:00Z",
+        ] {
+            assert_eq!(
+                session.status(utc(now), &calendar),
+                (MarketStatus::Closed, monday_pre),
+                "{}",
+                now
+            );
+        }
+
+        // Orari di giorni fa (es. dallo snapshot) spostati alla settimana dopo
+        assert_eq!(
+            session.status(utc("2026-07-07T14:00:00Z"), &calendar),
+            (MarketStatus::Open, utc("2026-07-07T20:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn trading_session_status_uses_holidays_of_its_timezone() {
+        // Londra (BST) senza pre e post-market
+        let session = TradingSession {
+            pre: (utc("2026-06-29T07:00:00Z"), utc("2026-06-29T07:00:00Z")),
+            regular: (utc("2026-06-29T07:00:00Z"), utc("2026-06-29T15:30:00Z")),
+            post: (utc("2026-06-29T15:30:00Z"), utc("2026-06-29T15:30:00Z")),
+            timezone: "Europe/London".to_string(),
+            gmtoffset: 3600,
+        };
+        let calendar = MarketCalendar {
+            holidays: vec![(
+                NaiveDate::from_ymd_opt(2026, 8, 31).unwrap(),
+                Some("Europe/London".to_string()),
+            )],
+        };
+
+        // Le festività USA non chiudono Londra
+        assert_eq!(
+            session.status(utc("2026-07-03T10:00:00Z"), &calendar),
+            (MarketStatus::Open, utc("2026-07-03T15:30:00Z"))
+        );
+        // Bank holiday dal file: dal venerdì sera si salta a martedì
+        assert_eq!(
+            session.status(utc("2026-08-28T16:00:00Z"), &calendar),
+            (MarketStatus::Closed, utc("2026-09-01T07:00:00Z"))
+        );
+        // ...che invece non tocca New York
+        assert!(
+            calendar.is_trading_day(NaiveDate::from_ymd_opt(2026, 8, 31).unwrap(), US_TIMEZONE)
+        );
+    }
+
+    #[test]
+    fn nyse_holidays_follow_the_exchange_rules() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        // Venerdì Santo 2028 (Pasqua il 16 aprile) e Thanksgiving 2030
+        assert!(is_nyse_holiday(date(2028, 4, 14)));
+        assert!(is_nyse_holiday(date(2030, 11, 28)));
+        // Juneteenth 2033 cade di domenica: chiuso il lunedì
+        assert!(is_nyse_holiday(date(2033, 6, 20)));
+        assert!(!is_nyse_holiday(date(2021, 6, 18)));
+        // Capodanno 2028 di sabato: il 31 dicembre si scambia
+        assert!(!is_nyse_holiday(date(2027, 12, 31)));
+        assert!(is_nyse_holiday(date(2027, 12, 24)));
+    }
+
+    fn simulator(seed: u64) -> SimulatorProvider {
+        SimulatorProvider::new(SimParams {
+            drift: 0.05,
+            volatility: 0.30,
+            seed,
+            steps_per_quote: 60,
+        })
+    }
+
+    #[test]
+    fn simulator_is_reproducible_for_a_seed() {
+        let quotes = |sim: &SimulatorProvider| -> Vec<f32> {
+            (0..5)
+                .map(|_| sim.fetch_quote("SIM00001").unwrap().price)
+                .collect()
+        };
+        let (a, b) = (simulator(7), simulator(7));
+        let first = quotes(&a);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(first, quotes(&b));
+        assert_ne!(first, quotes(&simulator(8)));
+
+        // Lo storico non cambia con le quote già servite e chiude sul prezzo iniziale
+        let fresh = simulator(7)
+            .fetch_history("SIM00001", ChartRange::Month1)
+            .unwrap();
+        let after = a.fetch_history("SIM00001", ChartRange::Month1).unwrap();
+        let closes = |h: &History| h.iter().map(|c| c.close).collect::<Vec<_>>();
+        assert_eq!(closes(&fresh), closes(&after));
+        let anchor = a.initial_price("SIM00001") as f32;
+        assert!((fresh.last().unwrap().close - anchor).abs() < anchor * 1e-5);
+    }
+
+    const SPARK_KEY: &str = "/v7/finance/spark?symbols=AAPL,MSFT&range=1d&interval=5m";
+
+    fn spark_body(symbols: &[&str]) -> String {
+        let result: Vec<_> = symbols
+            .iter()
+            .map(|s| serde_json::json!({ "symbol": s, "response": [] }))
+            .collect();
+        serde_json::json!({ "spark": { "result": result, "error": null } }).to_string()
+    }
+
+    fn spark_symbols(body: &str) -> Vec<String> {
+        let value: serde_json::Value = serde_json::from_str(body).unwrap();
+        value["spark"]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["symbol"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn replay_session(name: &str, entries: &[SessionEntry]) -> SessionReplay {
+        let path = std::env::temp_dir().join(format!("stock_tracker_{}.jsonl", name));
+        let body: String = entries
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap() + "\n")
+            .collect();
+        fs::write(&path, body).unwrap();
+        let replay = SessionReplay::load(path.to_str().unwrap(), 1.0).unwrap();
+        let _ = fs::remove_file(&path);
+        replay
+    }
+
+    #[test]
+    fn split_spark_key_gives_one_key_per_symbol() {
+        assert_eq!(
+            split_spark_key(SPARK_KEY).unwrap(),
+            [
+                (
+                    "AAPL".to_string(),
+                    "/v7/finance/spark?symbols=AAPL&range=1d&interval=5m".to_string()
+                ),
+                (
+                    "MSFT".to_string(),
+                    "/v7/finance/spark?symbols=MSFT&range=1d&interval=5m".to_string()
+                ),
+            ]
+        );
+        assert!(split_spark_key("/v8/finance/chart/AAPL?range=1d").is_none());
+    }
+
+    #[test]
+    fn replay_recombines_spark_batches_per_symbol() {
+        let replay = replay_session(
+            "spark_split",
+            &[SessionEntry {
+                time: utc("2026-07-02T14:00:00Z"),
+                url: format!("https://query1.finance.yahoo.com{}", SPARK_KEY),
+                status: 200,
+                body: spark_body(&["AAPL", "MSFT"]),
+            }],
+        );
+
+        // Batch con un ordine diverso da quello registrato, anche da un altro host
+        let body = replay
+            .respond(
+                "http://127.0.0.1:8080/v7/finance/spark?symbols=MSFT,AAPL&range=1d&interval=5m",
+            )
+            .unwrap();
+        assert_eq!(spark_symbols(&body), ["MSFT", "AAPL"]);
+
+        // Simbolo mai registrato: manca dal batch, gli altri rispondono
+        let body = replay
+            .respond("/v7/finance/spark?symbols=AAPL,TSLA&range=1d&interval=5m")
+            .unwrap();
+        assert_eq!(spark_symbols(&body), ["AAPL"]);
+        assert!(
+            replay
+                .respond("/v7/finance/spark?symbols=TSLA&range=1d&interval=5m")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn replay_applies_spark_http_errors_to_every_symbol() {
+        let entry = SessionEntry {
+            time: utc("2026-07-02T14:00:00Z"),
+            url: SPARK_KEY.to_string(),
+            status: 429,
+            body: "Too Many Requests".to_string(),
+        };
+        for symbol in ["AAPL", "MSFT"] {
+            let single = spark_entry_for(&entry, symbol);
+            assert_eq!(single.status, 429);
+        }
+
+        let replay = replay_session("spark_error", &[entry]);
+        for url in [
+            SPARK_KEY,
+            "/v7/finance/spark?symbols=MSFT&range=1d&interval=5m",
+        ] {
+            assert!(matches!(
+                replay.respond(url),
+                Err(FetchError::Http { status: 429, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn replay_lookup_picks_latest_response_before_the_clock() {
+        let entry = |time: &str, body: &str| SessionEntry {
+            time: utc(time),
+            url: String::new(),
+            status: 200,
+            body: body.to_string(),
+        };
+        // Orologio della sessione fermo circa alle 10:06
+        let replay = SessionReplay {
+            responses: HashMap::from([
+                (
+                    "/a".to_string(),
+                    vec![
+                        entry("2026-07-02T10:00:00Z", "a0"),
+                        entry("2026-07-02T10:05:00Z", "a1"),
+                        entry("2026-07-02T10:08:00Z", "a2"),
+                    ],
+                ),
+                ("/b".to_string(), vec![entry("2026-07-02T10:07:00Z", "b0")]),
+            ]),
+            start: utc("2026-07-02T10:06:00Z"),
+            end: utc("2026-07-02T10:10:00Z"),
+            started_at: Instant::now(),
+            speed: 1.0,
+        };
+
+        assert_eq!(replay.lookup("/a").unwrap(), "a1");
+        // Nessuna risposta ancora "arrivata": si usa la prima
+        assert_eq!(replay.lookup("/b").unwrap(), "b0");
+        assert!(matches!(replay.lookup("/c"), Err(FetchError::Io(_))));
+    }
 }